@@ -0,0 +1,80 @@
+//! Orbit camera for debugging, enabled with the `orbit_camera` feature.
+//!
+//! Drag with the left mouse button to rotate around the focus point, scroll to zoom.
+use bevy::input::mouse::{AccumulatedMouseMotion, AccumulatedMouseScroll, MouseScrollUnit};
+use bevy::prelude::*;
+
+/// Radians the camera turns per pixel dragged
+const ROTATE_SPEED: f32 = 0.005;
+/// How much one scroll line changes the distance to the focus point
+const ZOOM_SPEED: f32 = 0.1;
+/// Pixels of scroll worth one line, for touchpads
+const PIXELS_PER_LINE: f32 = 16.0;
+
+/// Makes a camera orbit around `focus`, its `Transform` is driven by this
+#[derive(Component, Clone, Debug)]
+pub struct OrbitCamera {
+    pub focus: Vec3,
+    pub radius: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl Default for OrbitCamera {
+    fn default() -> Self {
+        // the same point of view as the static camera
+        Self {
+            focus: Vec3::ZERO,
+            radius: 15.0,
+            yaw: 0.0,
+            pitch: 0.0,
+        }
+    }
+}
+
+impl OrbitCamera {
+    fn transform(&self) -> Transform {
+        let rotation = Quat::from_euler(EulerRot::YXZ, self.yaw, self.pitch, 0.0);
+        Transform::from_translation(self.focus + rotation * Vec3::Z * self.radius)
+            .looking_at(self.focus, Vec3::Y)
+    }
+}
+
+pub struct OrbitCameraPlugin;
+
+impl Plugin for OrbitCameraPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, orbit_camera);
+    }
+}
+
+fn orbit_camera(
+    buttons: Res<ButtonInput<MouseButton>>,
+    motion: Res<AccumulatedMouseMotion>,
+    scroll: Res<AccumulatedMouseScroll>,
+    mut cameras: Query<(&mut OrbitCamera, &mut Transform)>,
+) {
+    let drag = if buttons.pressed(MouseButton::Left) {
+        motion.delta
+    } else {
+        Vec2::ZERO
+    };
+    let lines = match scroll.unit {
+        MouseScrollUnit::Line => scroll.delta.y,
+        MouseScrollUnit::Pixel => scroll.delta.y / PIXELS_PER_LINE,
+    };
+    for (mut orbit, mut transform) in cameras.iter_mut() {
+        if drag != Vec2::ZERO {
+            orbit.yaw -= drag.x * ROTATE_SPEED;
+            // stop just short of the poles, looking_at breaks down right above the focus
+            let limit = std::f32::consts::FRAC_PI_2 - 0.01;
+            orbit.pitch = (orbit.pitch - drag.y * ROTATE_SPEED).clamp(-limit, limit);
+        }
+        if lines != 0.0 {
+            orbit.radius = (orbit.radius * (1.0 - lines * ZOOM_SPEED)).max(1.0);
+        }
+        if orbit.is_changed() {
+            *transform = orbit.transform();
+        }
+    }
+}
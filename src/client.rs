@@ -1,29 +1,194 @@
 //! The client plugin.
-use crate::shared::{shared_config, SharedPlugin, SERVER_ADDR};
+use crate::shared::{
+    hashed_color, link_conditioner, log_plugin, parse_ron_scene, private_key, private_key_path,
+    protocol_id, server_addr, shared_config, shared_config_with_mode, spawn_position, CarrierId,
+    Channel1, Channel2, ChatMessage, ClientHello, ComponentA, CurrentTick, Fragment,
+    FragmentReassembler, GameScore, NetId, NetPosition, Ping, PlayerInput, Pong,
+    RequestSceneSnapshot, SceneDelta, SceneSnapshot, SharedPlugin, PLAYER_SPEED, PRIVATE_KEY_ENV,
+    PRIVATE_KEY_FILE_ENV,
+};
+use bevy::ecs::entity::EntityHashMap;
+use bevy::ecs::system::SystemState;
 use bevy::prelude::*;
+use bevy::utils::HashSet;
+#[cfg(feature = "inspector")]
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 pub use lightyear::prelude::client::*;
 use lightyear::prelude::*;
+use lightyear::shared::config::Mode;
+use std::collections::VecDeque;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
 pub struct ExampleClientPlugin;
 
+impl ExampleClientPlugin {
+    /// Configure the client in code rather than through resources and environment variables.
+    /// What isn't set keeps the value `ExampleClientPlugin` would use
+    pub fn builder() -> ExampleClientPluginBuilder {
+        ExampleClientPluginBuilder::default()
+    }
+}
+
+/// Built by [`ExampleClientPlugin::builder`]
+#[derive(Clone, Debug, Default)]
+pub struct ExampleClientPluginBuilder {
+    server_addr: Option<SocketAddr>,
+}
+
+impl ExampleClientPluginBuilder {
+    /// Address of the server to connect to, [`server_addr`] by default
+    pub fn server_addr(mut self, addr: SocketAddr) -> Self {
+        self.server_addr = Some(addr);
+        self
+    }
+
+    pub fn build(self) -> ConfiguredClientPlugin {
+        ConfiguredClientPlugin(self)
+    }
+}
+
+/// [`ExampleClientPlugin`] with the settings of an [`ExampleClientPluginBuilder`]. They are
+/// inserted as the resources `ExampleClientPlugin` reads, over the ones already present
+pub struct ConfiguredClientPlugin(ExampleClientPluginBuilder);
+
+impl Plugin for ConfiguredClientPlugin {
+    fn build(&self, app: &mut App) {
+        if let Some(addr) = self.0.server_addr {
+            app.insert_resource(ServerAddress(addr));
+        }
+        app.add_plugins(ExampleClientPlugin);
+    }
+}
+
+/// The server [`ExampleClientPlugin`] connects to, [`server_addr`] when absent. Insert it as
+/// a resource before adding the plugin, or use [`ExampleClientPluginBuilder::server_addr`]
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ServerAddress(pub SocketAddr);
+
+/// How far behind the server the interpolated (non-owned) entities are rendered.
+///
+/// The delay is the largest of `min_delay` and `send_interval_ratio` times the server's
+/// replication interval, so that there is always a snapshot to interpolate towards. Insert
+/// it as a resource before adding [`ExampleClientPlugin`] to change it.
+#[derive(Resource, Clone, Debug)]
+pub struct InterpolationDelaySettings {
+    pub min_delay: Duration,
+    pub send_interval_ratio: f32,
+}
+
+impl Default for InterpolationDelaySettings {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_millis(50),
+            send_interval_ratio: 2.0,
+        }
+    }
+}
+
+/// How many ticks back the client can roll back to. It's lightyear's
+/// `PredictionConfig::maximum_predicted_ticks`: the client predicts at most that many ticks
+/// ahead of the server (more latency is covered by input delay), so a rollback never
+/// re-simulates more ticks than that, and we keep our inputs (see [`InputHistory`]) for as
+/// many ticks to replay them. Insert it as a resource before adding [`ExampleClientPlugin`]
+/// to change it.
+#[derive(Resource, Clone, Debug)]
+pub struct RollbackSettings {
+    pub history_ticks: u16,
+}
+
+impl Default for RollbackSettings {
+    fn default() -> Self {
+        Self { history_ticks: 100 }
+    }
+}
+
+/// How fast the [`VisualError`] left by a correction fades, as a rate per second: after `t`
+/// seconds, `exp(-decay_rate * t)` of it is left. Higher snaps faster, lower glides longer.
+/// Insert it as a resource before adding [`ExampleClientPlugin`] to change it.
+#[derive(Resource, Clone, Debug)]
+pub struct VisualErrorSettings {
+    pub decay_rate: f32,
+}
+
+impl Default for VisualErrorSettings {
+    fn default() -> Self {
+        Self { decay_rate: 15.0 }
+    }
+}
+
 const CLIENT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4000);
 
+/// How the client retries after losing its connection: the n-th attempt waits
+/// `base * 2^(n-1)`, capped at `max`, and it gives up after `max_attempts` attempts
+#[derive(Resource, Clone, Debug)]
+pub struct ReconnectConfig {
+    pub base: Duration,
+    pub max: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            max_attempts: 10,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// How long to wait before the given attempt (starting at 1)
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Sent when a reconnection attempt is scheduled
+#[derive(Event, Debug, Clone)]
+pub struct ReconnectAttempt {
+    pub attempt: u32,
+    pub delay: Duration,
+}
+
+/// The pending reconnection, if any
+#[derive(Resource, Default)]
+struct ReconnectState {
+    attempt: u32,
+    timer: Option<Timer>,
+}
+
 /// Here we create the lightyear [`ClientPlugins`]
-fn build_client_plugin() -> ClientPlugins {
+fn build_client_plugin(
+    server: SocketAddr,
+    interpolation_delay: &InterpolationDelaySettings,
+    rollback: &RollbackSettings,
+) -> ClientPlugins {
     // Authentication is where you specify how the client should connect to the server
     // This is where you provide the server address.
     let auth = Authentication::Manual {
-        server_addr: SERVER_ADDR,
+        server_addr: server,
         client_id: 0,
-        private_key: Key::default(),
-        protocol_id: 0,
+        // must match the server's key and protocol id, see MRE_PRIVATE_KEY and MRE_PROTOCOL_ID
+        private_key: private_key().unwrap_or_else(|| {
+            warn!(
+                "No private key: set {} or {}, or start the server from this directory first \
+                 so that it writes {:?}. Connecting with the default key",
+                PRIVATE_KEY_ENV,
+                PRIVATE_KEY_FILE_ENV,
+                private_key_path()
+            );
+            Key::default()
+        }),
+        protocol_id: protocol_id(),
     };
     // The IoConfig will specify the transport to use.
     let io = IoConfig {
         // the address specified here is the client_address, because we open a UDP socket on the client
         transport: ClientTransport::UdpSocket(CLIENT_ADDR),
+        conditioner: link_conditioner(),
         ..default()
     };
     // The NetConfig specifies how we establish a connection with the server.
@@ -38,33 +203,1002 @@ fn build_client_plugin() -> ClientPlugins {
         // part of the config needs to be shared between the client and server
         shared: shared_config(),
         net: net_config,
+        interpolation: InterpolationConfig {
+            delay: InterpolationDelay {
+                min_delay: interpolation_delay.min_delay,
+                send_interval_ratio: interpolation_delay.send_interval_ratio,
+            },
+        },
+        prediction: PredictionConfig {
+            maximum_predicted_ticks: rollback.history_ticks,
+            ..default()
+        },
+        ..default()
+    };
+    ClientPlugins::new(config)
+}
+
+/// The lightyear [`ClientPlugins`] of the local client of a host server. It doesn't go
+/// through any transport, it talks to the server running in the same `App`
+fn build_host_client_plugin() -> ClientPlugins {
+    let config = ClientConfig {
+        shared: shared_config_with_mode(Mode::HostServer),
+        net: NetConfig::Local { id: 0 },
         ..default()
     };
     ClientPlugins::new(config)
 }
 
+/// The client half of [`crate::server::ExampleHostServerPlugin`]. Unlike
+/// [`ExampleClientPlugin`] it leaves the default plugins, the shared plugin and the camera
+/// to the server plugin
+pub struct HostClientPlugin;
+
+impl Plugin for HostClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(build_host_client_plugin());
+        app.add_systems(Startup, connect_host_client);
+    }
+}
+
 impl Plugin for ExampleClientPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(DefaultPlugins);
+        app.add_plugins(DefaultPlugins.set(log_plugin()));
+        #[cfg(feature = "inspector")]
         app.add_plugins(WorldInspectorPlugin::new());
+        #[cfg(feature = "orbit_camera")]
+        if !app.is_plugin_added::<crate::camera::OrbitCameraPlugin>() {
+            app.add_plugins(crate::camera::OrbitCameraPlugin);
+        }
         // add lightyear plugins
-        app.add_plugins(build_client_plugin());
+        let server = app
+            .world()
+            .get_resource::<ServerAddress>()
+            .map_or_else(server_addr, |addr| addr.0);
+        let interpolation_delay = app
+            .world()
+            .get_resource::<InterpolationDelaySettings>()
+            .cloned()
+            .unwrap_or_default();
+        let rollback = app
+            .world()
+            .get_resource::<RollbackSettings>()
+            .cloned()
+            .unwrap_or_default();
+        app.add_plugins(build_client_plugin(server, &interpolation_delay, &rollback));
+        app.insert_resource(rollback);
         // add our shared plugin containing the protocol + other shared behaviour
         app.add_plugins(SharedPlugin);
         // add our client-specific logic. Here we will just connect to the server
-        app.add_systems(Startup, connect_client);
+        app.add_systems(Startup, (spawn_camera, spawn_player_list, connect_client));
+        app.add_systems(Update, send_client_hello);
+        // The scene of the server
+        app.add_systems(
+            Update,
+            (
+                request_scene_snapshot.run_if(is_connected.and(
+                    bevy::input::common_conditions::input_just_pressed(SCENE_REQUEST_KEY),
+                )),
+                receive_scene_snapshots,
+            ),
+        );
+        app.add_systems(Update, apply_scene_deltas.after(receive_scene_snapshots));
+
+        // Entities we were given the authority over
+        app.add_systems(Update, increment_authoritative_component_a);
+
+        // Show our entity right away, until the server's arrives
+        app.add_systems(Update, (prespawn_own_entity, reconcile_prespawned).chain());
+
+        // Chat: send what gets written to `OutgoingChat`, log what the server broadcasts
+        app.add_event::<OutgoingChat>();
+        app.add_systems(Update, (send_chat, receive_chat));
+
+        // Mirror the resources of the server
+        app.add_systems(Update, receive_game_score);
 
-        // app.add_systems(Startup, spawn_camera);
+        // Network diagnostics, toggled with F3
+        app.add_systems(Startup, spawn_debug_overlay);
+        app.add_systems(
+            Update,
+            (
+                toggle_debug_overlay.run_if(bevy::input::common_conditions::input_just_pressed(
+                    DEBUG_OVERLAY_KEY,
+                )),
+                update_debug_overlay,
+            ),
+        );
+
+        // Measure the latency
+        app.init_resource::<NetworkRtt>();
+        app.add_systems(Update, (send_ping, receive_pong).run_if(is_connected));
+
+        // Retry when the connection drops
+        app.init_resource::<ReconnectConfig>();
+        app.init_resource::<ReconnectState>();
+        app.add_event::<ReconnectAttempt>();
+        app.add_systems(Update, (schedule_reconnect, reconnect_client).chain());
+        // Move our entity ahead of the server, replayed by lightyear when it rolls back
+        app.init_resource::<InputHistory>();
+        app.init_resource::<VisualErrorSettings>();
+        app.init_resource::<PreRollbackPositions>();
+        app.add_systems(
+            PreUpdate,
+            (
+                record_pre_rollback_positions.before(PredictionSet::CheckRollback),
+                capture_visual_errors.after(PredictionSet::Rollback),
+            ),
+        );
+        app.add_systems(
+            FixedUpdate,
+            (send_player_input, predict_player_movement).chain(),
+        );
+        app.add_systems(
+            Update,
+            (
+                spawn_player_cubes,
+                (decay_visual_errors, sync_player_transforms).chain(),
+                update_player_list,
+            ),
+        );
+        app.add_systems(
+            Update,
+            (
+                log_predicted_entities,
+                log_interpolated_entities,
+                log_removed_components,
+                log_player_parts,
+                check_parts_have_parent,
+            ),
+        );
     }
 }
 
 /// Connect to the server
 fn connect_client(mut commands: Commands) {
-    commands.spawn(Camera2d);
     commands.connect_client();
 }
 
-// fn spawn_camera(mut commands: Commands){
-//     commands.spawn(Camera3d::default());
+/// Plan the next reconnection attempt when we get disconnected, and forget about past
+/// attempts once connected again
+fn schedule_reconnect(
+    config: Res<ReconnectConfig>,
+    mut state: ResMut<ReconnectState>,
+    mut connect_events: EventReader<ClientConnectEvent>,
+    mut disconnect_events: EventReader<ClientDisconnectEvent>,
+    mut attempt_writer: EventWriter<ReconnectAttempt>,
+) {
+    if !connect_events.is_empty() {
+        connect_events.clear();
+        *state = ReconnectState::default();
+    }
+    for _ in disconnect_events.read() {
+        if state.attempt >= config.max_attempts {
+            warn!(
+                "Giving up on reconnecting after {} attempts",
+                config.max_attempts
+            );
+            state.timer = None;
+            continue;
+        }
+        state.attempt += 1;
+        let delay = config.delay_for(state.attempt);
+        info!("Reconnecting in {:?} (attempt {})", delay, state.attempt);
+        state.timer = Some(Timer::new(delay, TimerMode::Once));
+        attempt_writer.send(ReconnectAttempt {
+            attempt: state.attempt,
+            delay,
+        });
+    }
+}
+
+/// Connect again once the backoff delay is over
+fn reconnect_client(time: Res<Time>, mut state: ResMut<ReconnectState>, mut commands: Commands) {
+    let Some(timer) = state.timer.as_mut() else {
+        return;
+    };
+    if timer.tick(time.delta()).finished() {
+        state.timer = None;
+        commands.connect_client();
+    }
+}
 
-// }
+/// Look at the players from above, they are spawned around the origin
+fn spawn_camera(mut commands: Commands) {
+    let camera = commands
+        .spawn((
+            Camera3d::default(),
+            Transform::from_xyz(0.0, 0.0, 15.0).looking_at(Vec3::ZERO, Vec3::Y),
+        ))
+        .id();
+    #[cfg(feature = "orbit_camera")]
+    commands
+        .entity(camera)
+        .insert(crate::camera::OrbitCamera::default());
+    commands.spawn((
+        DirectionalLight::default(),
+        Transform::from_xyz(4.0, 8.0, 10.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+}
+
+/// Marks the text listing the replicated players
+#[derive(Component)]
+struct PlayerList;
+
+fn spawn_player_list(mut commands: Commands) {
+    commands.spawn((
+        Text::new(""),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        },
+        PlayerList,
+    ));
+}
+
+/// Key showing and hiding the [`DebugOverlay`]
+pub const DEBUG_OVERLAY_KEY: KeyCode = KeyCode::F3;
+
+/// Text in the top right corner with the network diagnostics: round-trip time, bandwidth,
+/// tick and number of players. Hidden until [`DEBUG_OVERLAY_KEY`] is pressed
+#[derive(Component)]
+struct DebugOverlay;
+
+fn spawn_debug_overlay(mut commands: Commands) {
+    commands.spawn((
+        Text::new(""),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            right: Val::Px(10.0),
+            ..default()
+        },
+        Visibility::Hidden,
+        DebugOverlay,
+    ));
+}
+
+fn toggle_debug_overlay(mut overlay: Query<&mut Visibility, With<DebugOverlay>>) {
+    for mut visibility in overlay.iter_mut() {
+        visibility.toggle_visible_hidden();
+    }
+}
+
+/// Refresh the overlay every second, the bandwidth is what our transport sent and received
+/// during that second
+fn update_debug_overlay(
+    time: Res<Time>,
+    rtt: Res<NetworkRtt>,
+    tick_manager: Res<TickManager>,
+    connection: Res<ClientConnection>,
+    players: Query<(), (With<CarrierId>, With<Confirmed>)>,
+    mut overlay: Query<&mut Text, With<DebugOverlay>>,
+    mut timer: Local<Option<Timer>>,
+    mut last_bytes: Local<(usize, usize)>,
+) {
+    let timer =
+        timer.get_or_insert_with(|| Timer::new(Duration::from_secs(1), TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let Ok(mut text) = overlay.get_single_mut() else {
+        return;
+    };
+    let (sent, received) = connection.client.io().map_or((0, 0), |io| {
+        (io.stats().bytes_sent, io.stats().bytes_received)
+    });
+    let (last_sent, last_received) = std::mem::replace(&mut *last_bytes, (sent, received));
+    text.0 = format!(
+        "RTT: {} ms\nUp: {} B/s\nDown: {} B/s\nTick: {}\nPlayers: {}",
+        rtt.0.as_millis(),
+        sent.saturating_sub(last_sent),
+        received.saturating_sub(last_received),
+        tick_manager.tick().0,
+        players.iter().count()
+    );
+}
+
+/// List the `Name` and `ComponentA` of every player the server replicated to us
+fn update_player_list(
+    players: Query<(&Name, &ComponentA), With<Confirmed>>,
+    mut list: Query<&mut Text, With<PlayerList>>,
+) {
+    let Ok(mut text) = list.get_single_mut() else {
+        return;
+    };
+    let lines: Vec<String> = players
+        .iter()
+        .map(|(name, component_a)| format!("{}: {}", name, component_a.0))
+        .collect();
+    text.0 = lines.join("\n");
+}
+
+/// The color of the players carried by `client_id`, the same on every client
+pub fn carrier_color(client_id: ClientId) -> Color {
+    hashed_color(client_id.to_bits())
+}
+
+/// Give every player a cube colored after its carrier, see [`carrier_color`]. Only the
+/// predicted and interpolated copies are drawn, the confirmed entities just hold the raw
+/// server state, `CarrierId` included
+fn spawn_player_cubes(
+    players: Query<
+        (
+            Entity,
+            &NetPosition,
+            Option<&Predicted>,
+            Option<&Interpolated>,
+        ),
+        (
+            Added<NetPosition>,
+            Or<(With<Predicted>, With<Interpolated>)>,
+        ),
+    >,
+    carriers: Query<&CarrierId, With<Confirmed>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    for (entity, position, predicted, interpolated) in players.iter() {
+        let confirmed = predicted
+            .and_then(|predicted| predicted.confirmed_entity)
+            .or(interpolated.map(|interpolated| interpolated.confirmed_entity));
+        let color = confirmed
+            .and_then(|confirmed| carriers.get(confirmed).ok())
+            .map_or(Color::WHITE, |carrier_id| carrier_color(carrier_id.0));
+        commands.entity(entity).insert((
+            Mesh3d(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
+            MeshMaterial3d(materials.add(color)),
+            Transform::from_translation(position.0),
+        ));
+    }
+}
+
+/// Move the cubes to the replicated positions, offset by what's left of their
+/// [`VisualError`]
+fn sync_player_transforms(
+    mut players: Query<
+        (&NetPosition, Option<&VisualError>, &mut Transform),
+        Or<(Changed<NetPosition>, Changed<VisualError>)>,
+    >,
+) {
+    for (position, error, mut transform) in players.iter_mut() {
+        transform.translation = position.0 + error.map_or(Vec3::ZERO, |error| error.0);
+    }
+}
+
+/// How far from its [`NetPosition`] a predicted entity is drawn. A rollback moves the
+/// position straight to the corrected one, and this takes the difference so that the cube
+/// stays where it was drawn, then [`decay_visual_errors`] brings it to the position over a
+/// few frames. The position itself is never touched, only the `Transform`
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct VisualError(pub Vec3);
+
+/// The positions of the predicted entities just before lightyear checks for a rollback
+#[derive(Resource, Debug, Default)]
+struct PreRollbackPositions(EntityHashMap<Vec3>);
+
+fn record_pre_rollback_positions(
+    players: Query<(Entity, &NetPosition), With<Predicted>>,
+    mut positions: ResMut<PreRollbackPositions>,
+) {
+    positions.0.clear();
+    positions.0.extend(
+        players
+            .iter()
+            .map(|(entity, position)| (entity, position.0)),
+    );
+}
+
+/// Turn the jumps made by a rollback into [`VisualError`]s. Nothing else moves the predicted
+/// entities between the two systems, the ticks of this frame only run after
+fn capture_visual_errors(
+    positions: Res<PreRollbackPositions>,
+    mut players: Query<(Entity, &NetPosition, Option<&mut VisualError>), With<Predicted>>,
+    mut commands: Commands,
+) {
+    for (entity, position, error) in players.iter_mut() {
+        let Some(before) = positions.0.get(&entity) else {
+            continue;
+        };
+        let jump = *before - position.0;
+        if jump == Vec3::ZERO {
+            continue;
+        }
+        match error {
+            Some(mut error) => error.0 += jump,
+            None => {
+                commands.entity(entity).insert(VisualError(jump));
+            }
+        }
+    }
+}
+
+/// Shrink the [`VisualError`]s at the rate of the [`VisualErrorSettings`], down to zero once
+/// they are too small to see. The spent ones are left at zero rather than removed, so that
+/// the transform is synced one last time
+fn decay_visual_errors(
+    time: Res<Time>,
+    settings: Res<VisualErrorSettings>,
+    mut errors: Query<&mut VisualError>,
+) {
+    let factor = (-settings.decay_rate * time.delta_secs()).exp();
+    for mut error in errors.iter_mut() {
+        if error.0 == Vec3::ZERO {
+            continue;
+        }
+        error.0 *= factor;
+        if error.0.length_squared() < 1e-6 {
+            error.0 = Vec3::ZERO;
+        }
+    }
+}
+
+/// Connect the local client of a host server, the server plugin already spawned a camera
+fn connect_host_client(mut commands: Commands) {
+    commands.connect_client();
+}
+
+fn receive_game_score(
+    mut score: ResMut<GameScore>,
+    mut score_events: EventReader<ClientMessageEvent<GameScore>>,
+) {
+    if let Some(event) = score_events.read().last() {
+        *score = *event.message();
+    }
+}
+
+/// How often the client measures the round-trip time
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The last round-trip time measured with a [`Ping`]. It only has the precision of a tick
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct NetworkRtt(pub Duration);
+
+fn send_ping(
+    time: Res<Time>,
+    tick: Res<CurrentTick>,
+    rtt: Res<NetworkRtt>,
+    mut connection: ResMut<ClientConnectionManager>,
+    mut timer: Local<Option<Timer>>,
+) {
+    let timer = timer.get_or_insert_with(|| Timer::new(PING_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let mut ping = Ping {
+        sent_tick: tick.0,
+        rtt_ms: rtt.0.as_millis() as u32,
+    };
+    // unreliable, so that a retransmission or an earlier lost packet isn't counted in the RTT
+    if let Err(err) = connection.send_message::<Channel2, _>(&mut ping) {
+        error!("Failed to send a ping: {:?}", err);
+    }
+}
+
+/// The round-trip time is the number of ticks elapsed since the ping was sent
+fn receive_pong(
+    config: Res<ClientConfig>,
+    tick: Res<CurrentTick>,
+    mut rtt: ResMut<NetworkRtt>,
+    mut pong_events: EventReader<ClientMessageEvent<Pong>>,
+) {
+    for event in pong_events.read() {
+        let ticks = (tick.0 - event.message().sent_tick).max(0) as u32;
+        rtt.0 = config.shared.tick.tick_duration * ticks;
+        info!("RTT: {:?}", rtt.0);
+    }
+}
+
+/// Once the server gave us the authority over our entity (`AUTHORITY_KEY` on the server),
+/// we are the ones moving its `ComponentA`: add one every second. Lightyear sends it to the
+/// server, which forwards it to the other clients
+fn increment_authoritative_component_a(
+    time: Res<Time>,
+    mut owned: Query<(Entity, &mut ComponentA), With<HasAuthority>>,
+    mut timer: Local<Option<Timer>>,
+) {
+    let timer =
+        timer.get_or_insert_with(|| Timer::new(Duration::from_secs(1), TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (entity, mut component_a) in owned.iter_mut() {
+        component_a.0 += 1;
+        debug!("We set the ComponentA of {} to {}", entity, component_a.0);
+    }
+}
+
+/// Key asking the server for the scene again, see [`RequestSceneSnapshot`]
+pub const SCENE_REQUEST_KEY: KeyCode = KeyCode::F5;
+
+/// Marks the root of the scene received from the server, replaced by the next snapshot
+#[derive(Component)]
+struct SceneSnapshotRoot;
+
+fn request_scene_snapshot(mut connection: ResMut<ClientConnectionManager>) {
+    if let Err(err) = connection.send_message::<Channel1, _>(&mut RequestSceneSnapshot) {
+        error!("Failed to ask for the scene: {:?}", err);
+    }
+}
+
+/// Spawn the scene the server sends when we connect or when we ask for it, see
+/// [`SceneSnapshot`]. Large scenes come in [`Fragment`]s holding the bincode encoded
+/// snapshot
+fn receive_scene_snapshots(
+    app_type_registry: Res<AppTypeRegistry>,
+    mut scenes: ResMut<Assets<DynamicScene>>,
+    roots: Query<Entity, With<SceneSnapshotRoot>>,
+    mut snapshot_events: EventReader<ClientMessageEvent<SceneSnapshot>>,
+    mut fragment_events: EventReader<ClientMessageEvent<Fragment>>,
+    mut reassembler: Local<FragmentReassembler>,
+    mut commands: Commands,
+) {
+    let reassembled = fragment_events
+        .read()
+        .filter_map(|event| match reassembler.push(event.message().clone()) {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!("Dropped a scene fragment: {}", err);
+                None
+            }
+        })
+        .filter_map(
+            |bytes| match bincode::deserialize::<SceneSnapshot>(&bytes) {
+                Ok(snapshot) => Some(snapshot),
+                Err(err) => {
+                    error!("Received a scene that can't be decoded: {}", err);
+                    None
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+    let snapshots = snapshot_events
+        .read()
+        .map(|event| event.message().clone())
+        .chain(reassembled);
+    for snapshot in snapshots {
+        match parse_ron_scene(&snapshot.ron, &app_type_registry) {
+            Ok(scene) => {
+                info!("Spawning the scene sent by the server");
+                for root in roots.iter() {
+                    commands.entity(root).despawn_recursive();
+                }
+                commands
+                    .spawn(DynamicSceneRoot(scenes.add(scene)))
+                    .insert((Name::new("Scene snapshot"), SceneSnapshotRoot));
+            }
+            Err(err) => error!("Failed to parse the scene sent by the server: {}", err),
+        }
+    }
+}
+
+/// Apply the [`SceneDelta`]s to the scene spawned from the last [`SceneSnapshot`]. The
+/// entities of that scene whose [`NetId`] is in a delta are despawned, and the upserted ones
+/// are written in their place under the same root
+fn apply_scene_deltas(
+    world: &mut World,
+    delta_events: &mut SystemState<EventReader<ClientMessageEvent<SceneDelta>>>,
+) {
+    let deltas: Vec<SceneDelta> = delta_events
+        .get_mut(world)
+        .read()
+        .map(|event| event.message().clone())
+        .collect();
+    if deltas.is_empty() {
+        return;
+    }
+    let app_type_registry = world.resource::<AppTypeRegistry>().clone();
+    for delta in deltas {
+        let scene = match parse_ron_scene(&delta.upserts, &app_type_registry) {
+            Ok(scene) => scene,
+            Err(err) => {
+                error!(
+                    "Failed to parse the scene changes sent by the server: {}",
+                    err
+                );
+                continue;
+            }
+        };
+        // the ids of the upserted entities, found by loading them on the side
+        let mut replaced: HashSet<NetId> = delta.removed.into_iter().collect();
+        let mut scratch = World::new();
+        scratch.insert_resource(app_type_registry.clone());
+        if scene.write_to_world(&mut scratch, &mut default()).is_ok() {
+            replaced.extend(scratch.query::<&NetId>().iter(&scratch).copied());
+        }
+
+        let root = world
+            .query_filtered::<Entity, With<SceneSnapshotRoot>>()
+            .iter(world)
+            .next()
+            .unwrap_or_else(|| {
+                world
+                    .spawn((Name::new("Scene snapshot"), SceneSnapshotRoot))
+                    .id()
+            });
+        let stale: Vec<Entity> = world
+            .query::<(Entity, &NetId, &Parent)>()
+            .iter(world)
+            .filter(|(_, net_id, parent)| parent.get() == root && replaced.contains(*net_id))
+            .map(|(entity, _, _)| entity)
+            .collect();
+        for entity in stale {
+            world.entity_mut(entity).despawn_recursive();
+        }
+
+        let mut entity_map = EntityHashMap::default();
+        if let Err(err) = scene.write_to_world(world, &mut entity_map) {
+            error!(
+                "Failed to apply the scene changes sent by the server: {}",
+                err
+            );
+            continue;
+        }
+        for entity in entity_map.values() {
+            if world.get::<Parent>(*entity).is_none() {
+                world.entity_mut(*entity).set_parent(root);
+            }
+        }
+    }
+}
+
+/// How long a [`Prespawned`] entity waits for the server's entity before it is dropped
+const PRESPAWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Local stand-in for our entity, spawned as soon as we connect so that we don't stare at an
+/// empty scene until the first replication interval. It is drawn where the server spawns
+/// players, see [`spawn_position`].
+///
+/// The matching key is the [`CarrierId`]: once a predicted entity whose confirmed entity is
+/// carried by `client_id` shows up, it takes over and the stand-in is despawned. The
+/// stand-in is never sent to the server, it is not a lightyear prespawned entity.
+#[derive(Component)]
+struct Prespawned {
+    client_id: ClientId,
+    timer: Timer,
+}
+
+fn prespawn_own_entity(
+    mut connect_events: EventReader<ClientConnectEvent>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    for event in connect_events.read() {
+        let client_id = event.client_id();
+        commands.spawn((
+            Prespawned {
+                client_id,
+                timer: Timer::new(PRESPAWN_TIMEOUT, TimerMode::Once),
+            },
+            Mesh3d(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
+            MeshMaterial3d(materials.add(carrier_color(client_id))),
+            Transform::from_translation(spawn_position(client_id)),
+        ));
+    }
+}
+
+/// Replace the [`Prespawned`] stand-ins with the predicted entities matching them, or drop
+/// them when the server didn't send one in time
+fn reconcile_prespawned(
+    time: Res<Time>,
+    mut prespawned: Query<(Entity, &mut Prespawned)>,
+    predicted: Query<&Predicted>,
+    carriers: Query<&CarrierId, With<Confirmed>>,
+    mut commands: Commands,
+) {
+    for (entity, mut stand_in) in prespawned.iter_mut() {
+        let matched = predicted.iter().any(|predicted| {
+            predicted
+                .confirmed_entity
+                .and_then(|confirmed| carriers.get(confirmed).ok())
+                .is_some_and(|carrier_id| carrier_id.0 == stand_in.client_id)
+        });
+        if matched {
+            debug!(
+                "The server entity of {:?} replaced its stand-in",
+                stand_in.client_id
+            );
+            commands.entity(entity).despawn_recursive();
+        } else if stand_in.timer.tick(time.delta()).finished() {
+            warn!(
+                "No entity carried by {:?} arrived within {:?}, dropping its stand-in",
+                stand_in.client_id, PRESPAWN_TIMEOUT
+            );
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Send a line of chat to every client
+#[derive(Event, Debug, Clone)]
+pub struct OutgoingChat(pub String);
+
+fn send_chat(
+    mut connection: ResMut<ClientConnectionManager>,
+    mut chat_events: EventReader<OutgoingChat>,
+) {
+    for event in chat_events.read() {
+        let mut message = ChatMessage {
+            // the server knows who we are and fills this in
+            sender: ClientId::Local(0),
+            text: event.0.clone(),
+        };
+        if let Err(err) = connection.send_message::<Channel1, _>(&mut message) {
+            error!("Failed to send a chat message: {:?}", err);
+        }
+    }
+}
+
+fn receive_chat(mut chat_events: EventReader<ClientMessageEvent<ChatMessage>>) {
+    for event in chat_events.read() {
+        let message = event.message();
+        info!("[chat] {:?}: {}", message.sender, message.text);
+    }
+}
+
+/// Tell the server which tick rate we run at once connected
+fn send_client_hello(
+    config: Res<ClientConfig>,
+    mut connection: ResMut<ClientConnectionManager>,
+    mut connect_events: EventReader<ClientConnectEvent>,
+) {
+    for _ in connect_events.read() {
+        let mut hello = ClientHello {
+            tick_duration: config.shared.tick.tick_duration,
+        };
+        if let Err(err) = connection.send_message::<Channel1, _>(&mut hello) {
+            error!("Failed to send the client hello: {:?}", err);
+        }
+    }
+}
+
+/// Our own entity shows up twice: the [`Confirmed`] copy holding what the server sent, and
+/// the [`Predicted`] copy we simulate ahead of it
+fn log_predicted_entities(predicted: Query<(Entity, &ComponentA, &Predicted), Added<Predicted>>) {
+    for (entity, component_a, predicted) in predicted.iter() {
+        info!(
+            "Predicting entity {} ({:?}), confirmed entity is {:?}",
+            entity, component_a, predicted.confirmed_entity
+        );
+    }
+}
+
+/// The `ComponentA(0)` part the server attaches to every player comes back as a child of the
+/// player's confirmed entity
+fn log_player_parts(
+    parts: Query<(Entity, &Parent, &ComponentA), (Added<Parent>, With<Confirmed>)>,
+) {
+    for (entity, parent, component_a) in parts.iter() {
+        info!(
+            "Entity {} ({:?}) is a child of {}",
+            entity,
+            component_a,
+            parent.get()
+        );
+    }
+}
+
+/// The parts are in the replication group of their player, so they are applied in the same
+/// update as the player entity and always find it. A part without a `Parent` means the group
+/// was split up on the way
+fn check_parts_have_parent(
+    parts: Query<Entity, (Added<ParentSync>, Without<Parent>, With<Confirmed>)>,
+) {
+    for entity in parts.iter() {
+        error!(
+            "Entity {} arrived before its parent, it isn't replicated with its group",
+            entity
+        );
+    }
+}
+
+/// Components removed on the server go away on the client as well, log it to check they do
+fn log_removed_components(
+    mut removed_a: RemovedComponents<ComponentA>,
+    mut removed_name: RemovedComponents<Name>,
+) {
+    for entity in removed_a.read() {
+        info!("ComponentA was removed from entity {}", entity);
+    }
+    for entity in removed_name.read() {
+        info!("Name was removed from entity {}", entity);
+    }
+}
+
+/// Entities carried by other clients are [`Interpolated`] between the server snapshots, they
+/// never get a [`Predicted`] copy
+fn log_interpolated_entities(
+    interpolated: Query<(Entity, &ComponentA, &Interpolated), Added<Interpolated>>,
+) {
+    for (entity, component_a, interpolated) in interpolated.iter() {
+        info!(
+            "Interpolating entity {} ({:?}), confirmed entity is {:?}",
+            entity, component_a, interpolated.confirmed_entity
+        );
+    }
+}
+
+/// The direction we held at each of the last [`RollbackSettings::history_ticks`] ticks, which
+/// [`predict_player_movement`] replays when lightyear rolls back and re-simulates them
+#[derive(Resource, Debug, Default)]
+pub struct InputHistory(VecDeque<(Tick, Vec2)>);
+
+impl InputHistory {
+    /// The direction held at `tick`, zero if it's not in the history
+    pub fn get(&self, tick: Tick) -> Vec2 {
+        self.0
+            .iter()
+            .rev()
+            .find(|(input_tick, _)| *input_tick == tick)
+            .map_or(Vec2::ZERO, |(_, direction)| *direction)
+    }
+}
+
+/// Send the direction held on the keyboard (WASD or arrows) to the server, once per tick,
+/// and keep it in the [`InputHistory`]. Releasing the keys sends one last still input, as the
+/// server repeats the last one (and stops by itself shortly after if that one is lost).
+/// Nothing is sampled while re-simulating past ticks
+fn send_player_input(
+    keys: Res<ButtonInput<KeyCode>>,
+    tick: Res<CurrentTick>,
+    settings: Res<RollbackSettings>,
+    rollback: Option<Res<Rollback>>,
+    mut history: ResMut<InputHistory>,
+    mut was_moving: Local<bool>,
+    mut connection: ResMut<ClientConnectionManager>,
+) {
+    if rollback.is_some_and(|rollback| rollback.is_rollback()) {
+        return;
+    }
+    let mut direction = Vec2::ZERO;
+    if keys.any_pressed([KeyCode::KeyW, KeyCode::ArrowUp]) {
+        direction.y += 1.0;
+    }
+    if keys.any_pressed([KeyCode::KeyS, KeyCode::ArrowDown]) {
+        direction.y -= 1.0;
+    }
+    if keys.any_pressed([KeyCode::KeyA, KeyCode::ArrowLeft]) {
+        direction.x -= 1.0;
+    }
+    if keys.any_pressed([KeyCode::KeyD, KeyCode::ArrowRight]) {
+        direction.x += 1.0;
+    }
+    history.0.push_back((tick.0, direction.normalize_or_zero()));
+    while history.0.len() > settings.history_ticks as usize {
+        history.0.pop_front();
+    }
+    let moving = direction != Vec2::ZERO;
+    if !moving && !std::mem::replace(&mut *was_moving, false) {
+        return;
+    }
+    *was_moving = moving;
+    let mut input = PlayerInput {
+        tick: tick.0,
+        direction: direction.normalize_or_zero(),
+    };
+    if let Err(err) = connection.send_message::<Channel2, _>(&mut input) {
+        error!("Failed to send input: {:?}", err);
+    }
+}
+
+/// Move our predicted entity the way the server will, by the input of the tick being
+/// simulated. During a rollback that's a past tick, whose input comes from the
+/// [`InputHistory`]. Only our own entity is [`Predicted`], so every match is ours
+fn predict_player_movement(
+    config: Res<ClientConfig>,
+    tick_manager: Res<TickManager>,
+    rollback: Option<Res<Rollback>>,
+    history: Res<InputHistory>,
+    mut players: Query<&mut NetPosition, With<Predicted>>,
+) {
+    let tick = rollback.as_deref().map_or(tick_manager.tick(), |rollback| {
+        tick_manager.tick_or_rollback_tick(rollback)
+    });
+    let direction = history.get(tick);
+    if direction == Vec2::ZERO {
+        return;
+    }
+    let delta =
+        direction.extend(0.0) * PLAYER_SPEED * config.shared.tick.tick_duration.as_secs_f32();
+    for mut position in players.iter_mut() {
+        position.0 += delta;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{IncrementConfig, LobbyConfig};
+    use crate::shared::CarrierId;
+    use crate::test_utils::Stepper;
+    use lightyear::prelude::client::Correction;
+
+    /// The server replicates the entity we carry, with its `Name`, right after we connect
+    #[test]
+    fn client_receives_its_entity() {
+        let mut stepper = Stepper::new(&[1]);
+        // the first client of the stepper carries the entity of the default scene
+        let client_id = ClientId::Netcode(0);
+        let received = stepper.step_until(100, |stepper| {
+            let world = stepper.client_world(0);
+            world
+                .query_filtered::<(&CarrierId, &Name), (With<ComponentA>, With<Confirmed>)>()
+                .iter(world)
+                .any(|(carrier_id, name)| {
+                    carrier_id.0 == client_id && name.as_str() == "Replicated entity"
+                })
+        });
+        assert!(received, "our entity wasn't replicated");
+    }
+
+    /// Whether lightyear rolled back since it was last reset
+    #[derive(Resource, Default)]
+    struct RolledBack(bool);
+
+    fn note_rollback(rollback: Res<Rollback>, mut rolled_back: ResMut<RolledBack>) {
+        if rollback.is_rollback() {
+            rolled_back.0 = true;
+        }
+    }
+
+    /// Push our predicted [`ComponentA`] away from the server's value: the next server
+    /// update doesn't match the predicted history, and the rollback it causes must bring the
+    /// prediction back. Nothing on the client simulates `ComponentA`, so the re-simulated
+    /// value is the server's
+    #[test]
+    fn rollback_converges_after_a_divergence() {
+        let mut stepper = Stepper::with_server(&[1], |server| {
+            server.insert_resource(LobbyConfig {
+                use_rooms: false,
+                ..default()
+            });
+            // the server changing the component is what sends the update checked against
+            // the prediction
+            server.insert_resource(IncrementConfig {
+                interval: Some(Duration::from_millis(100)),
+            });
+        });
+        stepper.clients[0]
+            .init_resource::<RolledBack>()
+            .add_systems(FixedUpdate, note_rollback);
+
+        let mut predicted = None;
+        let spawned = stepper.step_until(200, |stepper| {
+            let world = stepper.client_world(0);
+            predicted = world
+                .query_filtered::<Entity, (With<Predicted>, With<ComponentA>)>()
+                .iter(world)
+                .next();
+            predicted.is_some()
+        });
+        assert!(spawned, "our entity wasn't predicted");
+        let predicted = predicted.unwrap();
+        // let the rollbacks of the first updates settle
+        for _ in 0..20 {
+            stepper.step();
+        }
+
+        let world = stepper.client_world(0);
+        world.get_mut::<ComponentA>(predicted).unwrap().0 += 1000;
+        world.resource_mut::<RolledBack>().0 = false;
+        let rolled_back = stepper.step_until(200, |stepper| {
+            stepper.client_world(0).resource::<RolledBack>().0
+        });
+        assert!(rolled_back, "the divergence didn't cause a rollback");
+
+        // after the update, the component holds the visual correction and the corrected
+        // value is kept aside until the next frame
+        let world = stepper.client_world(0);
+        let corrected = world
+            .get::<Correction<ComponentA>>(predicted)
+            .and_then(|correction| correction.current_correction.clone())
+            .unwrap_or_else(|| world.get::<ComponentA>(predicted).unwrap().clone());
+        let confirmed = world.get::<Predicted>(predicted).unwrap().confirmed_entity;
+        let server = world.get::<ComponentA>(confirmed.unwrap()).unwrap();
+        assert_eq!(corrected, *server);
+    }
+}
@@ -1,43 +1,92 @@
 //! This minimal example showcases how to setup the lightyear plugins.
 //!
 //! Run with
-//! - `cargo run -- server`
+//! - `cargo run -- server` (or just `cargo run`)
 //! - `cargo run -- client`
+//! - `cargo run -- host-server`
+//!
+//! `--addr` and `--port` change the server address, for the server and the client alike, e.g.
+//! `cargo run -- --addr 0.0.0.0 --port 6000 server`
+//!
+//! The server can run without rendering with `cargo run --features headless -- server`,
+//! and the world inspector is enabled with `--features inspector`. `--features orbit_camera`
+//! lets the camera be rotated with the mouse and zoomed with the wheel, and
+//! `--features hot_reload` spawns the scene again when its file changes
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 #![allow(dead_code)]
+// Bevy systems take their resources and queries as arguments
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
 
+#[cfg(feature = "orbit_camera")]
+mod camera;
 mod client;
 mod server;
 mod shared;
+#[cfg(test)]
+mod stress;
+#[cfg(test)]
+mod test_utils;
 
 use bevy::prelude::*;
 use clap::{Parser, Subcommand, ValueEnum};
+use std::net::{IpAddr, SocketAddr};
 
 /// CLI options to create an [`App`]
 #[derive(Parser, Debug)]
 #[command(version, about)]
 pub struct Cli {
+    /// Runs the server when omitted
     #[command(subcommand)]
-    pub mode: Mode,
+    pub mode: Option<Mode>,
+    /// IP of the server, the one in `MRE_SERVER_ADDR` or localhost by default
+    #[arg(long, global = true)]
+    pub addr: Option<IpAddr>,
+    /// Port of the server, the one in `MRE_SERVER_ADDR` or 5000 by default
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Mode {
+    /// Connect to a server
     Client,
+    /// Listen for clients, writing a secret key file to the current directory if no key is set
+    ///
+    /// Unless MRE_PRIVATE_KEY or MRE_PRIVATE_KEY_FILE is set, the server generates the secret
+    /// Netcode key and writes it to `private_key.hex` in the current directory, where the
+    /// clients started from there read it. Keep that file private
     Server,
+    /// Run the server and a client in the same process, writing the same key file as `server`
+    ///
+    /// Writes `private_key.hex` to the current directory like `server` does
+    HostServer,
 }
 
 fn main() {
     let cli = Cli::parse();
+    let mut server = server::ExampleServerPlugin::builder();
+    let mut client = client::ExampleClientPlugin::builder();
+    if cli.addr.is_some() || cli.port.is_some() {
+        let default = shared::server_addr();
+        let addr = SocketAddr::new(
+            cli.addr.unwrap_or(default.ip()),
+            cli.port.unwrap_or(default.port()),
+        );
+        server = server.addr(addr);
+        client = client.server_addr(addr);
+    }
     let mut app = App::new();
 
-    match cli.mode {
+    match cli.mode.unwrap_or(Mode::Server) {
         Mode::Client => {
-            app.add_plugins(client::ExampleClientPlugin);
+            app.add_plugins(client.build());
         }
         Mode::Server => {
-            app.add_plugins(server::ExampleServerPlugin);
+            app.add_plugins(server.build());
+        }
+        Mode::HostServer => {
+            app.add_plugins(server.host_server(true).build());
         }
     }
     app.run();
@@ -3,179 +3,5146 @@
 //!
 //! The server will:
 //! - spawn a new player entity for each client that connects
-//! - read inputs from the clients and move the player entities accordingly
+//! - read inputs from the clients and move the player entities accordingly, applying them
+//!   in the order of the ticks they were sent at
 //!
 //! Lightyear will handle the replication of entities automatically if you add a `Replicate` component to them.
+use bevy::asset::{AssetLoadError, AssetLoadFailedEvent, AssetPath};
+use bevy::ecs::system::SystemState;
 use bevy::log::{Level, LogPlugin};
 use bevy::prelude::*;
+use bevy::reflect::TypeRegistry;
+use bevy::scene::serde::{SceneDeserializer, SceneSerializer};
+use bevy::scene::SceneInstanceReady;
 use bevy::state::app::StatesPlugin;
 use bevy::state::commands;
-use bevy::tasks::IoTaskPool;
+use bevy::tasks::futures_lite::future;
+use bevy::tasks::{block_on, IoTaskPool, Task};
+use bevy::utils::{Duration, HashMap, HashSet, Instant};
+#[cfg(all(feature = "inspector", not(feature = "headless")))]
+use bevy_inspector_egui::bevy_egui::{egui, EguiContexts};
+#[cfg(all(feature = "inspector", not(feature = "headless")))]
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
+use bincode::Options;
+use lightyear::connection::server::{ConnectionRequestHandler, DeniedReason};
 use lightyear::prelude::server::*;
 use lightyear::prelude::*;
 use lightyear::server::relevance::room::Room;
+use lightyear::shared::config::Mode;
+use lightyear::shared::replication::components::ReplicationGroupId;
+use serde::de::{DeserializeSeed, IgnoredAny};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::File;
 use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::shared::{
-    shared_config, CarrierId, ComponentA, SharedPlugin, SERVER_ADDR, SERVER_REPLICATION_INTERVAL,
+    current_tick, hashed_color, key_to_hex, link_conditioner, log_plugin, parse_ron_scene,
+    private_key, private_key_path, protocol_id, server_addr, shared_config_with_mode,
+    spawn_position, split_into_fragments, tick_hz, CarrierId, Channel1, Channel2, ChatMessage,
+    ClientHello, ComponentA, ComponentB, CurrentTick, Fragment, FragmentConfig, GameScore, NetId,
+    NetIdAllocator, NetPosition, Ping, PlayerInput, Pong, RenameRequest, RequestSceneSnapshot,
+    SceneDelta, SceneSnapshot, SharedPlugin, PLAYER_SPEED, PRIVATE_KEY_ENV, SECONDARY_SERVER_ADDR,
+    SERVER_ADDR, SERVER_REPLICATION_INTERVAL, TICK_HZ_ENV, WEBSOCKET_SERVER_ADDR,
+    WEBTRANSPORT_SERVER_ADDR,
 };
 
 pub struct ExampleServerPlugin;
 
-/// Here we create the lightyear [`ServerPlugins`]
-fn build_server_plugin() -> ServerPlugins {
-    // The IoConfig will specify the transport to use.
-    let io = IoConfig {
+impl ExampleServerPlugin {
+    /// Configure the server in code rather than through resources and environment variables.
+    /// What isn't set keeps the value `ExampleServerPlugin` would use
+    pub fn builder() -> ExampleServerPluginBuilder {
+        ExampleServerPluginBuilder::default()
+    }
+}
+
+/// Built by [`ExampleServerPlugin::builder`]
+#[derive(Clone, Debug, Default)]
+pub struct ExampleServerPluginBuilder {
+    addr: Option<SocketAddr>,
+    tick_hz: Option<f64>,
+    max_clients: Option<usize>,
+    headless: bool,
+    host_server: bool,
+    transports: Vec<TransportKind>,
+}
+
+impl ExampleServerPluginBuilder {
+    /// Address of the main UDP transport, ignored once a [`Self::transport`] is given
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// Tick rate of the server, a rate that isn't a positive number is ignored with a warning
+    pub fn tick_hz(mut self, hz: f64) -> Self {
+        self.tick_hz = Some(hz);
+        self
+    }
+
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    /// Run without window and rendering, as with the `headless` feature
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+
+    /// Run a local client in the same `App`, as [`ExampleHostServerPlugin`] does
+    pub fn host_server(mut self, host_server: bool) -> Self {
+        self.host_server = host_server;
+        self
+    }
+
+    /// Listen on this transport, call it again to listen on several at once
+    pub fn transport(mut self, kind: TransportKind) -> Self {
+        self.transports.push(kind);
+        self
+    }
+
+    pub fn build(self) -> ConfiguredServerPlugin {
+        ConfiguredServerPlugin(self)
+    }
+}
+
+/// [`ExampleServerPlugin`] with the settings of an [`ExampleServerPluginBuilder`]. They are
+/// inserted as the resources `ExampleServerPlugin` reads, over the ones already present
+pub struct ConfiguredServerPlugin(ExampleServerPluginBuilder);
+
+impl Plugin for ConfiguredServerPlugin {
+    fn build(&self, app: &mut App) {
+        let builder = &self.0;
+        let mut settings = app
+            .world()
+            .get_resource::<ServerSettings>()
+            .cloned()
+            .unwrap_or_else(ServerSettings::load);
+        if let Some(addr) = builder.addr {
+            settings.addr = addr;
+        }
+        match builder.tick_hz {
+            Some(hz) if valid_tick_hz(hz) => settings.tick_hz = hz,
+            Some(hz) => warn!("Invalid tick_hz {}, using {}", hz, settings.tick_hz),
+            None => {}
+        }
+        if let Some(max_clients) = builder.max_clients {
+            settings.max_clients = max_clients;
+            let limits = app
+                .world()
+                .get_resource::<ConnectionLimits>()
+                .cloned()
+                .unwrap_or_default();
+            app.insert_resource(ConnectionLimits {
+                max_clients,
+                ..limits
+            });
+        }
+        app.insert_resource(settings);
+        if !builder.transports.is_empty() {
+            app.insert_resource(ServerTransports(builder.transports.clone()));
+        }
+        if builder.headless {
+            app.insert_resource(Headless);
+        }
+        if builder.host_server {
+            app.insert_resource(HostServerMode);
+        }
+        app.add_plugins(ExampleServerPlugin);
+        if builder.host_server {
+            app.add_plugins(crate::client::HostClientPlugin);
+        }
+    }
+}
+
+/// Makes [`ExampleServerPlugin`] run headless without the `headless` feature, see
+/// [`ExampleServerPluginBuilder::headless`]
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct Headless;
+
+/// Runs the server together with a local client in a single `App` (lightyear's
+/// `Mode::HostServer`), i.e. a listen server.
+///
+/// The local client lives in the server's `World`: replicated entities like the `ComponentA`
+/// carriers aren't copied over the network for it, it sees the server entities directly with
+/// no round-trip and no replication delay. Remote clients still connect as usual and get
+/// the entities replicated.
+pub struct ExampleHostServerPlugin;
+
+/// Tells [`ExampleServerPlugin`] that a local client runs in the same `App`
+#[derive(Resource, Clone, Copy, Debug)]
+struct HostServerMode;
+
+impl Plugin for ExampleHostServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ExampleServerPlugin::builder().host_server(true).build());
+    }
+}
+
+/// Serialization format used for scene files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SceneFormat {
+    #[default]
+    Ron,
+    /// Compact binary encoding, much faster to write and read than RON for large worlds
+    Bincode,
+}
+
+impl SceneFormat {
+    /// File extension used for scenes saved in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            SceneFormat::Ron => "ron",
+            SceneFormat::Bincode => "scn",
+        }
+    }
+
+    /// Pick the format from a file extension, anything that isn't `.scn` is treated as RON
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("scn") => SceneFormat::Bincode,
+            _ => SceneFormat::Ron,
+        }
+    }
+}
+
+/// Controls where and how [`create_save_scene`] writes the serialized scene.
+///
+/// The path may contain a `{client_id}` placeholder, which is replaced by the id of the
+/// client whose connection triggered the save, so that each client gets its own file.
+/// The extension of the path is replaced to match the chosen [`SceneFormat`].
+///
+/// Saves can also be triggered by hand by pressing `save_key`, in which case the placeholder
+/// is replaced by `server`.
+///
+/// Nothing is saved on WASM, as there's no filesystem to write to. Use
+/// [`serialize_current_scene`] to get the scene in memory there.
+///
+/// With `backup` enabled, an existing file is renamed to `scene.<timestamp>-<n>.ron.bak` before
+/// being overwritten, and only the `max_backups` most recent backups are kept.
+///
+/// RON scenes are indented over several lines when `pretty` is set, which makes them easy to
+/// review in version control, and written on a single line otherwise. The loader reads both.
+#[derive(Resource, Clone, Debug)]
+pub struct SceneSaveConfig {
+    pub path: PathBuf,
+    pub format: SceneFormat,
+    pub pretty: bool,
+    pub save_key: KeyCode,
+    pub backup: bool,
+    pub max_backups: usize,
+}
+
+impl Default for SceneSaveConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("assets/scene.ron"),
+            format: SceneFormat::Ron,
+            pretty: true,
+            save_key: KeyCode::F5,
+            backup: false,
+            max_backups: 5,
+        }
+    }
+}
+
+impl SceneSaveConfig {
+    /// Resolve the output path for the given client, expanding the `{client_id}` placeholder
+    pub fn path_for(&self, client_id: Option<ClientId>) -> PathBuf {
+        let id = client_id.map_or("server".to_string(), |id| id.to_bits().to_string());
+        let template = self.path.to_string_lossy();
+        PathBuf::from(template.replace("{client_id}", &id)).with_extension(self.format.extension())
+    }
+
+    /// Where [`autosave_scene`] writes, next to the manual saves: `scene.autosave.ron`
+    pub fn autosave_path(&self) -> PathBuf {
+        let path = self.path_for(None);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!("{}.autosave.{}", stem, self.format.extension()))
+    }
+
+    /// How many backups to keep around, `None` if backups are disabled
+    fn keep_backups(&self) -> Option<usize> {
+        self.backup.then_some(self.max_backups)
+    }
+}
+
+/// Periodic saves of the server world, written to [`SceneSaveConfig::autosave_path`] every
+/// `interval` when `enabled`. RON autosaves start with a comment holding the server tick
+#[derive(Resource, Clone, Debug)]
+pub struct AutosaveConfig {
+    pub interval: Duration,
+    pub enabled: bool,
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            enabled: false,
+        }
+    }
+}
+
+/// Environment variable holding the full URL of the scene to load on WASM, see
+/// [`SceneLoadConfig::url`]
+pub const SCENE_URL_ENV: &str = "MRE_SCENE_URL";
+
+/// Which scene [`spawn_scene`] loads at startup, relative to the `assets` folder.
+///
+/// The deserializer is picked from the file extension, see [`SceneFormat::from_path`].
+///
+/// On WASM the scene can instead be fetched from anywhere with a full `url` (a RON scene),
+/// which defaults to [`SCENE_URL_ENV`]. The browser has no environment variables, so insert
+/// the config before adding [`ExampleServerPlugin`] to set it there. Failures are reported
+/// with a [`SceneLoadFailed`] event.
+#[derive(Resource, Clone, Debug)]
+pub struct SceneLoadConfig {
+    pub path: PathBuf,
+    pub url: Option<String>,
+}
+
+impl Default for SceneLoadConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("scene.ron"),
+            url: std::env::var(SCENE_URL_ENV).ok(),
+        }
+    }
+}
+
+/// Scene spawned when there's no scene file to load, e.g. on a fresh clone or without a
+/// writable `assets` folder. Parsed directly, it doesn't go through the asset server
+pub const DEFAULT_SCENE_RON: &str = include_str!("../assets/default_scene.ron");
+
+/// Asset source serving the scene of [`SceneLoadConfig::url`]
+const SCENE_URL_SOURCE: &str = "scene-url";
+
+/// Despawn the loaded scene, its root and every entity it spawned. Players spawned by the
+/// server rather than by the scene are left alone
+#[derive(Event, Debug, Clone, Copy)]
+pub struct UnloadScene;
+
+/// Marks the entities spawned by a scene, see [`UnloadScene`]
+#[derive(Component, Debug, Default)]
+pub struct FromScene;
+
+/// Sent when the scene file changed on disk and the scene was spawned again
+#[derive(Event, Debug, Clone)]
+pub struct SceneReloaded {
+    pub path: Option<AssetPath<'static>>,
+}
+
+/// Sent when the scene [`spawn_scene`] asked for couldn't be loaded
+#[derive(Event, Debug, Clone)]
+pub struct SceneLoadFailed {
+    pub path: String,
+    pub error: String,
+}
+
+/// Errors that can happen while turning a world into scene data
+#[derive(Debug)]
+pub enum SceneSerializationError {
+    Ron(bevy::scene::ron::Error),
+    Bincode(bincode::Error),
+}
+
+impl fmt::Display for SceneSerializationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneSerializationError::Ron(err) => write!(f, "RON serialization failed: {err}"),
+            SceneSerializationError::Bincode(err) => {
+                write!(f, "bincode serialization failed: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneSerializationError {}
+
+/// Result of checking a loaded scene against the [`AppTypeRegistry`]
+#[derive(Event, Debug, Clone)]
+pub struct SceneValidationReport {
+    pub path: Option<AssetPath<'static>>,
+    /// Component types referenced by the scene that are missing from the registry, or that
+    /// are registered without `#[reflect(Component)]` and so can't be inserted
+    pub unknown_types: Vec<String>,
+}
+
+/// Sent when writing a scene to disk fails
+#[derive(Event, Debug, Clone)]
+pub struct SceneSaveFailed {
+    /// The client whose connection triggered the save, `None` for manual saves
+    pub client_id: Option<ClientId>,
+    pub error: String,
+}
+
+/// Sent once a scene has been fully written to disk
+#[derive(Event, Debug, Clone)]
+pub struct SceneSaved {
+    /// The client whose connection triggered the save, `None` for manual saves
+    pub client_id: Option<ClientId>,
+    pub path: PathBuf,
+    /// Number of bytes written to `path`
+    pub bytes: usize,
+}
+
+/// Sent instead of [`SceneSaved`] when a connect would write the exact same scene that is
+/// already on disk, in which case nothing is written
+#[derive(Event, Debug, Clone)]
+pub struct SceneUnchanged {
+    pub client_id: ClientId,
+    pub path: PathBuf,
+}
+
+/// Hash of the last scene written to each path, by any of the saves going through
+/// [`PendingSceneSaves::queue`]
+#[derive(Resource, Default)]
+struct SavedSceneHashes(HashMap<PathBuf, u64>);
+
+fn scene_hash(data: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A scene write running on the [`IoTaskPool`]
+struct PendingSceneSave {
+    client_id: Option<ClientId>,
+    path: PathBuf,
+    task: Task<std::io::Result<usize>>,
+}
+
+/// Scene writes that are still running, polled by [`poll_scene_saves`]
+#[derive(Resource, Default)]
+struct PendingSceneSaves(Vec<PendingSceneSave>);
+
+impl PendingSceneSaves {
+    /// Write the scene data in the background, the outcome is picked up by [`poll_scene_saves`].
+    /// The hash of `data` is recorded for `path` right away, so that [`create_save_scene`]
+    /// compares its scene to what this wrote and not to its own last save
+    fn queue(
+        &mut self,
+        saved_hashes: &mut SavedSceneHashes,
+        client_id: Option<ClientId>,
+        path: PathBuf,
+        data: Vec<u8>,
+        keep_backups: Option<usize>,
+    ) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            saved_hashes.0.insert(path.clone(), scene_hash(&data));
+            let task_path = path.clone();
+            let task = IoTaskPool::get().spawn(async move {
+                if let Some(max_backups) = keep_backups {
+                    backup_scene_file(&task_path, max_backups)?;
+                }
+                write_scene_file(&task_path, &data)
+            });
+            self.0.push(PendingSceneSave {
+                client_id,
+                path,
+                task,
+            });
+        }
+    }
+}
+
+/// The entities carried by each client, kept up to date by [`update_carrier_index`] from the
+/// [`CarrierId`] components, in the order they got it.
+///
+/// Entities can also be looked up by [`NetId`], the id that stays the same on the clients and
+/// across saves, unlike the `Entity`
+#[derive(Resource, Clone, Debug, Default)]
+pub struct CarrierIndex {
+    by_client: HashMap<ClientId, Vec<Entity>>,
+    by_net_id: HashMap<NetId, Entity>,
+}
+
+impl CarrierIndex {
+    /// The first entity the client carries, the one its inputs move
+    pub fn entity_for_client(&self, id: ClientId) -> Option<Entity> {
+        self.entities_for_client(id).first().copied()
+    }
+
+    /// Every entity the client carries
+    pub fn entities_for_client(&self, id: ClientId) -> &[Entity] {
+        self.by_client.get(&id).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn entity_for_net_id(&self, net_id: NetId) -> Option<Entity> {
+        self.by_net_id.get(&net_id).copied()
+    }
+}
+
+/// Key pausing and resuming replication, see [`ReplicationPaused`]
+pub const PAUSE_REPLICATION_KEY: KeyCode = KeyCode::F9;
+
+/// While set, no replication updates go out, clients keep the state they last received.
+/// The server keeps simulating, only the sending stops.
+///
+/// Lightyear pauses the replication of an entity while it doesn't have the `Replicating`
+/// marker of its `Replicate` bundle, so pausing removes it and resuming puts it back.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplicationPaused(pub bool);
+
+/// Key kicking the client that connected last, see [`kick_last_client`]
+pub const KICK_KEY: KeyCode = KeyCode::F8;
+
+/// Disconnect a client. Its entity and room membership are cleaned up as for any other
+/// disconnect
+#[derive(Event, Debug, Clone)]
+pub struct KickClient {
+    pub client_id: ClientId,
+    pub reason: String,
+}
+
+/// Key handing every client the authority over its own entity, see [`TransferAuthority`]
+pub const AUTHORITY_KEY: KeyCode = KeyCode::F7;
+
+/// Give a client the authority over a replicated entity: the server then accepts the
+/// updates of that client for it, and forwards them to the other clients. Only components
+/// registered `Bidirectional` (like `ComponentA`) can be sent back by the client.
+///
+/// [`transfer_authority`] refuses the transfer unless `to` is connected and carries
+/// `entity`, so that clients can't take over each other's entities.
+#[derive(Event, Debug, Clone)]
+pub struct TransferAuthority {
+    pub entity: Entity,
+    pub to: ClientId,
+}
+
+/// Key stopping the server, see [`stop_server`]
+pub const SHUTDOWN_KEY: KeyCode = KeyCode::F12;
+
+/// Ask the server to stop: pending scene saves are finished, replicated entities are
+/// despawned and the `App` exits. Sent on [`SHUTDOWN_KEY`] and on Ctrl-C
+#[derive(Event, Debug, Clone, Copy)]
+pub struct ShutdownRequest;
+
+/// Set by the Ctrl-C handler, turned into a [`ShutdownRequest`] on the next frame
+#[derive(Resource, Clone, Default)]
+struct ShutdownSignal(Arc<AtomicBool>);
+
+/// The room of its own a client gets when no shared room is used
+pub fn room_for_client(id: ClientId) -> RoomId {
+    RoomId(id.to_bits())
+}
+
+/// Rooms known by name, so that shared rooms don't have to borrow the id of some client.
+///
+/// Named rooms are given ids counting down from `u64::MAX`, far from the ids of
+/// [`room_for_client`], and keep the same id for as long as the server runs. The `"lobby"` and
+/// `"game"` rooms always exist.
+#[derive(Resource, Clone, Debug)]
+pub struct RoomRegistry {
+    rooms: HashMap<String, RoomId>,
+}
+
+impl RoomRegistry {
+    pub const LOBBY: RoomId = RoomId(u64::MAX);
+    pub const GAME: RoomId = RoomId(u64::MAX - 1);
+
+    /// The id of the room called `name`, registering it on first use
+    pub fn room(&mut self, name: &str) -> RoomId {
+        let next = RoomId(u64::MAX - self.rooms.len() as u64);
+        *self.rooms.entry(name.to_string()).or_insert(next)
+    }
+
+    /// The id of the room called `name`, if it was registered
+    pub fn get(&self, name: &str) -> Option<RoomId> {
+        self.rooms.get(name).copied()
+    }
+
+    /// The name a room was registered with
+    pub fn name_of(&self, room_id: RoomId) -> Option<&str> {
+        self.rooms
+            .iter()
+            .find(|(_, id)| **id == room_id)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+impl Default for RoomRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            rooms: HashMap::default(),
+        };
+        registry.room("lobby");
+        registry.room("game");
+        registry
+    }
+}
+
+/// How clients are grouped into rooms by [`add_replicate`].
+///
+/// By default every client gets its own room, see [`room_for_client`]. When `shared_room` is
+/// set (e.g. to [`RoomRegistry::LOBBY`]), every client and its entity join that single room
+/// instead, so players can see each other.
+///
+/// No room ever holds more than `capacity` clients: the clients past it are turned away
+/// and their entity isn't replicated, a [`RoomFull`] event is sent once for every connect
+/// turned away. The entities of that client are tried again the next time it connects.
+///
+/// Rooms are only used when `use_rooms` is set. Otherwise entities are replicated to every
+/// client and all of the above is ignored.
+#[derive(Resource, Clone, Debug)]
+pub struct LobbyConfig {
+    pub use_rooms: bool,
+    pub shared_room: Option<RoomId>,
+    pub capacity: usize,
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        Self {
+            use_rooms: true,
+            shared_room: None,
+            capacity: 8,
+        }
+    }
+}
+
+impl LobbyConfig {
+    /// The room the given client belongs to
+    pub fn room_for(&self, client_id: ClientId) -> RoomId {
+        self.shared_room
+            .unwrap_or_else(|| room_for_client(client_id))
+    }
+
+    /// How many clients a room may hold
+    pub fn room_capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Which clients [`add_replicate`] sends a player entity to, on top of the [`RelevanceMode`]
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub enum ReplicationTargetConfig {
+    /// Every client
+    #[default]
+    All,
+    /// Only the client carrying the entity, e.g. for private per-player state
+    Owner,
+    /// Only the given clients
+    Only(Vec<ClientId>),
+    /// Every client but the given ones
+    AllExcept(Vec<ClientId>),
+}
+
+impl ReplicationTargetConfig {
+    /// The target of the entity carried by `owner`
+    pub fn target_for(&self, owner: ClientId) -> NetworkTarget {
+        match self {
+            Self::All => NetworkTarget::All,
+            Self::Owner => NetworkTarget::Only(vec![owner]),
+            Self::Only(clients) => NetworkTarget::Only(clients.clone()),
+            Self::AllExcept(clients) => NetworkTarget::AllExcept(clients.clone()),
+        }
+    }
+}
+
+/// How [`add_replicate`] decides which clients receive an entity.
+///
+/// With `Rooms` the [`LobbyConfig`] applies. With `Distance` the rooms are not used at all: a
+/// client only receives the entities whose [`NetPosition`] is within `radius` of its own
+/// entity, see [`update_distance_relevance`].
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq)]
+pub enum RelevanceMode {
+    #[default]
+    Rooms,
+    Distance {
+        radius: f32,
+    },
+}
+
+/// Per-room replication send intervals, rooms without an entry use
+/// [`SERVER_REPLICATION_INTERVAL`].
+///
+/// The server never sends faster than the global `send_interval` of its
+/// `ReplicationConfig`, so only intervals longer than that make a difference (e.g. a
+/// spectator room that gets updates every 500ms).
+#[derive(Resource, Clone, Debug, Default)]
+pub struct RoomIntervals(pub HashMap<RoomId, Duration>);
+
+impl RoomIntervals {
+    /// The send interval to use for the entities of the given room
+    pub fn interval_for(&self, room_id: RoomId) -> Duration {
+        self.0
+            .get(&room_id)
+            .copied()
+            .unwrap_or(SERVER_REPLICATION_INTERVAL)
+    }
+}
+
+/// Rooms whose entities are frozen: [`increment_component_a`] leaves them alone
+#[derive(Resource, Clone, Debug, Default)]
+pub struct PausedRooms(pub HashSet<RoomId>);
+
+/// How often [`increment_component_a`] adds one to the `ComponentA` of every replicated
+/// entity, so that clients have something changing to watch. `None` keeps them still
+#[derive(Resource, Clone, Debug)]
+pub struct IncrementConfig {
+    pub interval: Option<Duration>,
+}
+
+impl Default for IncrementConfig {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// Priority of the player entities that don't have a [`ReplicationPriority`], above the
+/// default of 1 of everything else
+pub const PLAYER_PRIORITY: f32 = 10.0;
+
+/// How many times higher a client ranks the entity it carries than the other clients do
+pub const OWN_ENTITY_PRIORITY_BOOST: f32 = 10.0;
+
+/// Replication priority of an entity, read by [`add_replicate`] when it starts replicating
+/// the entity (insert it before that, changing it later has no effect).
+///
+/// Every `send_interval`, lightyear adds the priority of each replication group to what it
+/// accumulated while its updates were held back, and sends the groups with the highest
+/// accumulated priority first. Groups that don't fit in the bandwidth budget of that
+/// interval wait for the next one, with a higher accumulated priority. Without a bandwidth
+/// cap (the default) everything fits, so priorities only matter once the cap is enabled
+/// with `ServerConfig::packet`.
+///
+/// The priority applies to every client alike. On top of it, [`prioritize_own_entities`]
+/// ranks the entity a client carries [`OWN_ENTITY_PRIORITY_BOOST`] times higher for that
+/// client only.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct ReplicationPriority(pub f32);
+
+impl Default for ReplicationPriority {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+/// How many ticks ahead of the server an input is kept. Clients run a little ahead, so their
+/// inputs arrive before the tick they are for, but anything further out is dropped
+pub const INPUT_BUFFER_TICKS: i16 = 64;
+
+/// For how many ticks the last input of a client is repeated when nothing newer arrives.
+/// Clients send an input every tick while moving, so a longer gap means the ones after were
+/// lost, the release included, and the player stops instead of moving on forever
+pub const MAX_REPEATED_INPUT_TICKS: u16 = 8;
+
+/// The [`PlayerInput`]s received and not applied yet, by client and by the tick they are for,
+/// so that they are applied in tick order however they arrive. See [`buffer_player_input`]
+/// and [`apply_player_input`]
+#[derive(Resource, Clone, Debug, Default)]
+pub struct InputBuffer {
+    pub inputs: HashMap<(ClientId, Tick), PlayerInput>,
+    /// The last input applied for each client with the tick it was for, repeated on the
+    /// ticks it sent nothing for, up to [`MAX_REPEATED_INPUT_TICKS`]
+    pub last: HashMap<ClientId, (Tick, PlayerInput)>,
+}
+
+/// Sent when a client can't join its room because the room is full
+#[derive(Event, Debug, Clone)]
+pub struct RoomFull {
+    pub client_id: ClientId,
+    pub room_id: RoomId,
+}
+
+/// Groups the clients waiting in the lobby (the `shared_room` of the [`LobbyConfig`]) into
+/// games. As soon as `min_players` are waiting, up to `max_players` of them are moved to a
+/// new game room, registered in the [`RoomRegistry`] as `"game-<n>"`, and a
+/// [`MatchStarted`] is sent.
+///
+/// Nothing happens while there is no shared room, as every client is then alone in its own.
+#[derive(Resource, Clone, Debug)]
+pub struct Matchmaker {
+    pub min_players: usize,
+    pub max_players: usize,
+    /// Number of games started so far
+    pub games: u64,
+}
+
+impl Default for Matchmaker {
+    fn default() -> Self {
+        Self {
+            min_players: 2,
+            max_players: 4,
+            games: 0,
+        }
+    }
+}
+
+/// Sent by [`matchmake`] when clients leave the lobby for a new game room
+#[derive(Event, Debug, Clone)]
+pub struct MatchStarted {
+    pub room_id: RoomId,
+    pub players: Vec<ClientId>,
+}
+
+/// Number of clients currently in the room, 0 if the room doesn't exist
+pub fn room_client_count(rooms: &RoomManager, room_id: RoomId) -> usize {
+    rooms.get_room(room_id).map_or(0, |room| room.clients.len())
+}
+
+/// Key that logs the content of every room, see [`dump_rooms`]
+pub const DUMP_ROOMS_KEY: KeyCode = KeyCode::F6;
+
+/// Read-only view of room membership, since [`RoomManager`] doesn't expose its rooms for
+/// iteration
+pub trait RoomMembership {
+    /// Clients and entities of each of the given rooms, rooms that don't exist are skipped
+    fn membership(
+        &self,
+        room_ids: impl IntoIterator<Item = RoomId>,
+    ) -> Vec<(RoomId, Vec<ClientId>, Vec<Entity>)>;
+}
+
+impl RoomMembership for RoomManager {
+    fn membership(
+        &self,
+        room_ids: impl IntoIterator<Item = RoomId>,
+    ) -> Vec<(RoomId, Vec<ClientId>, Vec<Entity>)> {
+        room_ids
+            .into_iter()
+            .filter_map(|room_id| {
+                let room = self.get_room(room_id)?;
+                Some((
+                    room_id,
+                    room.clients.iter().copied().collect(),
+                    room.entities.iter().copied().collect(),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Ask the server to move a client and the entities it carries from one room to another
+#[derive(Event, Debug, Clone)]
+pub struct TransferClientRequest {
+    pub client_id: ClientId,
+    pub from: RoomId,
+    pub to: RoomId,
+}
+
+/// Sent by [`handle_transfer_requests`] for each [`TransferClientRequest`] carried out
+#[derive(Event, Debug, Clone)]
+pub struct ClientTransferred {
+    pub client_id: ClientId,
+    pub from: RoomId,
+    pub to: RoomId,
+}
+
+/// Move `client_id` and its carried `entities` from room `from` to room `to`.
+///
+/// The [`RoomManager`] doesn't know which entities a client carries, so the caller passes
+/// them, [`handle_transfer_requests`] takes them from the [`CarrierIndex`]. Rooms are
+/// created on demand, so `to` doesn't need to exist yet. Returns whether the client moved:
+/// `false`, with the rooms left untouched, if it isn't in `from`.
+pub fn transfer_client(
+    rooms: &mut RoomManager,
+    client_id: ClientId,
+    entities: &[Entity],
+    from: RoomId,
+    to: RoomId,
+) -> bool {
+    let in_from = rooms
+        .get_room(from)
+        .is_some_and(|room| room.clients.contains(&client_id));
+    if !in_from {
+        warn!(
+            "Can't transfer {:?} out of room {:?}, it isn't in there",
+            client_id, from
+        );
+        return false;
+    }
+    rooms.remove_client(client_id, from);
+    rooms.add_client(client_id, to);
+    for entity in entities {
+        rooms.remove_entity(*entity, from);
+        rooms.add_entity(*entity, to);
+    }
+    info!(
+        "Transferred {:?} from room {:?} to {:?}",
+        client_id, from, to
+    );
+    true
+}
+
+/// Where and how often the room of every client is saved, so that clients reconnecting
+/// after a restart find themselves back in their room.
+///
+/// Clients that don't come back are forgotten `ttl` after they were last seen.
+#[derive(Resource, Clone, Debug)]
+pub struct RoomPersistence {
+    pub path: PathBuf,
+    pub save_interval: Duration,
+    pub ttl: Duration,
+}
+
+impl Default for RoomPersistence {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("rooms.ron"),
+            save_interval: Duration::from_secs(10),
+            ttl: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The room a client was in, `last_seen` is in seconds since the unix epoch
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RoomMember {
+    client_id: ClientId,
+    room: u64,
+    last_seen: u64,
+}
+
+/// The room of every client, connected or seen less than [`RoomPersistence::ttl`] ago
+#[derive(Resource, Default)]
+struct PersistedRooms(HashMap<ClientId, RoomMember>);
+
+impl PersistedRooms {
+    /// The room `client_id` is in. It follows the transfers, unlike [`LobbyConfig::room_for`]
+    /// which is only used for the clients not recorded yet
+    fn room_of(&self, client_id: ClientId, lobby_config: &LobbyConfig) -> RoomId {
+        self.0.get(&client_id).map_or_else(
+            || lobby_config.room_for(client_id),
+            |member| RoomId(member.room),
+        )
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The entity of a client that disconnected at `since` (elapsed [`Time`]), kept until
+/// [`ConnectionLimits::reconnect_grace`] runs out
+#[derive(Component, Debug)]
+pub struct AwaitingReconnect {
+    pub since: Duration,
+}
+
+/// The `ComponentA` of the clients whose entity was despawned and when it was, to recreate
+/// the entity if they come back within [`ConnectionLimits::forget_after`]
+#[derive(Resource, Default)]
+struct DespawnedPlayers(HashMap<ClientId, (usize, Duration)>);
+
+/// Marks entities that [`add_replicate`] already started replicating, so that later connects
+/// don't insert `Replicate` (and spawn another child) on them again
+#[derive(Component, Debug, Default)]
+pub struct Replicated;
+
+/// A transport the server listens on, along with the address it binds
+#[derive(Clone, Debug)]
+pub enum TransportKind {
+    /// Plain UDP sockets, on [`server_addr`] by default
+    Udp { addr: SocketAddr },
+    /// TCP based, for networks that block UDP. Clients expect it on [`WEBSOCKET_SERVER_ADDR`]
+    WebSocket { addr: SocketAddr },
+    /// Needed for browser (WASM) clients. Clients expect it on [`WEBTRANSPORT_SERVER_ADDR`]
+    WebTransport {
+        addr: SocketAddr,
+        certificate: CertificateSource,
+    },
+    /// In-process channels, one per client with the address the server sees it at. Only
+    /// used by the tests
+    #[cfg(test)]
+    Channels {
+        channels: Vec<(
+            SocketAddr,
+            crossbeam_channel::Receiver<Vec<u8>>,
+            crossbeam_channel::Sender<Vec<u8>>,
+        )>,
+    },
+}
+
+impl Default for TransportKind {
+    fn default() -> Self {
+        TransportKind::Udp {
+            addr: server_addr(),
+        }
+    }
+}
+
+/// The transports the server listens on, all at the same time.
+///
+/// Insert it as a resource before adding [`ExampleServerPlugin`] to listen on something else
+/// than UDP, e.g. on both UDP and WebSocket so clients behind firewalls can still join.
+/// By default the server listens for UDP on both [`server_addr`] and
+/// [`SECONDARY_SERVER_ADDR`], clients can connect to either one.
+#[derive(Resource, Clone, Debug)]
+pub struct ServerTransports(pub Vec<TransportKind>);
+
+impl Default for ServerTransports {
+    fn default() -> Self {
+        Self::udp([server_addr(), SECONDARY_SERVER_ADDR])
+    }
+}
+
+impl ServerTransports {
+    /// Listen for UDP on each of the given addresses
+    pub fn udp(addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        Self(
+            addrs
+                .into_iter()
+                .map(|addr| TransportKind::Udp { addr })
+                .collect(),
+        )
+    }
+}
+
+/// Where the WebTransport TLS certificate comes from
+#[derive(Clone, Debug, Default)]
+pub enum CertificateSource {
+    /// Generate a self-signed certificate at startup
+    #[default]
+    SelfSigned,
+    /// Load the certificate and its private key from PEM files
+    PemFiles { cert: PathBuf, key: PathBuf },
+}
+
+/// Digest of the WebTransport certificate, clients need it to pin the self-signed
+/// certificate (e.g. `serverCertificateHashes` in the browser)
+#[derive(Resource, Clone, Debug)]
+pub struct CertificateDigest(pub String);
+
+/// Load or generate the WebTransport certificate
+fn load_certificate(source: &CertificateSource) -> Identity {
+    let self_signed = || Identity::self_signed(["localhost", "127.0.0.1", "::1"]).unwrap();
+    match source {
+        CertificateSource::SelfSigned => self_signed(),
+        CertificateSource::PemFiles { cert, key } => {
+            load_pemfiles(cert, key).unwrap_or_else(|err| {
+                error!(
+                    "Failed to load certificate from {:?}, generating a self-signed one: {}",
+                    cert, err
+                );
+                self_signed()
+            })
+        }
+    }
+}
+
+/// Load the certificate and its private key from PEM files. wtransport reads them with
+/// `tokio::fs`, which only works inside a tokio runtime, so they are read on a
+/// single-threaded one made for the occasion
+fn load_pemfiles(cert: &Path, key: &Path) -> Result<Identity, String> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .map_err(|err| format!("can't start a tokio runtime: {}", err))?;
+    runtime
+        .block_on(Identity::load_pemfiles(cert, key))
+        .map_err(|err| err.to_string())
+}
+
+/// Build the lightyear transport for the given kind, along with the certificate digest for
+/// WebTransport
+fn server_transport(kind: &TransportKind) -> (ServerTransport, Option<CertificateDigest>) {
+    match kind {
         // the address specified here is the server_address, because we open a UDP socket on the server
-        transport: ServerTransport::UdpSocket(SERVER_ADDR),
+        TransportKind::Udp { addr } => (ServerTransport::UdpSocket(*addr), None),
+        TransportKind::WebSocket { addr } => (
+            ServerTransport::WebSocketServer { server_addr: *addr },
+            None,
+        ),
+        TransportKind::WebTransport { addr, certificate } => {
+            let certificate = load_certificate(certificate);
+            let digest = certificate.certificate_chain().as_slice()[0]
+                .hash()
+                .to_string();
+            info!("WebTransport certificate digest: {}", digest);
+            (
+                ServerTransport::WebTransportServer {
+                    server_addr: *addr,
+                    certificate,
+                },
+                Some(CertificateDigest(digest)),
+            )
+        }
+        #[cfg(test)]
+        TransportKind::Channels { channels } => (
+            ServerTransport::Channels {
+                channels: channels.clone(),
+            },
+            None,
+        ),
+    }
+}
+
+/// The Netcode config shared by every transport, with the protocol id and private key
+/// clients have to use to connect.
+///
+/// If no private key is configured a random one is generated and written to
+/// [`private_key_path`], where the clients read it from. When the file can't be written the
+/// key is only kept in memory, and no client can connect until a key is configured. The key
+/// itself is never logged.
+fn netcode_config() -> NetcodeConfig {
+    let key = private_key().unwrap_or_else(|| {
+        let key: Key = rand::random();
+        let path = private_key_path();
+        match write_private_key(&path, &key) {
+            Ok(()) => info!(
+                "No private key configured, generated one in {:?} for the clients",
+                path
+            ),
+            Err(err) => error!(
+                "No private key configured and the generated one can't be written to {:?}: {}. \
+                 Set {} on the server and the clients to let them connect",
+                path, err, PRIVATE_KEY_ENV
+            ),
+        }
+        key
+    });
+    NetcodeConfig::default()
+        .with_protocol_id(protocol_id())
+        .with_key(key)
+}
+
+/// Write `key` hex encoded to `path`, readable by its owner only
+fn write_private_key(path: &Path, key: &Key) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(key_to_hex(key).as_bytes())
+}
+
+/// Environment variable holding the path of the [`ServerSettings`] file, e.g.
+/// `MRE_CONFIG=/etc/mre/server.toml`
+pub const CONFIG_PATH_ENV: &str = "MRE_CONFIG";
+
+/// Settings file read when [`CONFIG_PATH_ENV`] is unset
+pub const DEFAULT_CONFIG_PATH: &str = "server.toml";
+
+/// Server settings that can be changed without recompiling, read from a TOML file by
+/// [`ServerSettings::load`]. Every field is optional, missing ones keep the built-in value:
+///
+/// ```toml
+/// addr = "0.0.0.0:5000"
+/// replication_interval_ms = 100
+/// tick_hz = 64.0
+/// max_clients = 32
+/// ```
+///
+/// Insert it as a resource before adding [`ExampleServerPlugin`] to skip the file. The
+/// clients still read their tick rate from [`TICK_HZ_ENV`], so set both to change it.
+#[derive(Resource, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ServerSettings {
+    /// Address of the main UDP transport
+    pub addr: SocketAddr,
+    pub replication_interval_ms: u64,
+    pub tick_hz: f64,
+    pub max_clients: usize,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            addr: server_addr(),
+            replication_interval_ms: SERVER_REPLICATION_INTERVAL.as_millis() as u64,
+            tick_hz: tick_hz(),
+            max_clients: ConnectionLimits::default().max_clients,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Read the file at [`CONFIG_PATH_ENV`] (or [`DEFAULT_CONFIG_PATH`]), falling back to the
+    /// defaults when there is no such file or it can't be parsed
+    pub fn load() -> Self {
+        let path = std::env::var(CONFIG_PATH_ENV).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.into());
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => {
+                info!("No settings file at {}, using the defaults", path);
+                return Self::default();
+            }
+        };
+        let settings: Self = match toml::from_str(&text) {
+            Ok(settings) => settings,
+            Err(err) => {
+                warn!(
+                    "Invalid settings file {}: {}, using the defaults",
+                    path, err
+                );
+                return Self::default();
+            }
+        };
+        if valid_tick_hz(settings.tick_hz) {
+            settings
+        } else {
+            warn!(
+                "Invalid tick_hz {} in {}, using {}",
+                settings.tick_hz,
+                path,
+                tick_hz()
+            );
+            Self {
+                tick_hz: tick_hz(),
+                ..settings
+            }
+        }
+    }
+
+    pub fn replication_interval(&self) -> Duration {
+        Duration::from_millis(self.replication_interval_ms)
+    }
+
+    /// The duration of a tick, [`tick_hz`] is used instead of a `tick_hz` that isn't a
+    /// positive number
+    pub fn tick_duration(&self) -> Duration {
+        let hz = if valid_tick_hz(self.tick_hz) {
+            self.tick_hz
+        } else {
+            warn!("Invalid tick_hz {}, using {}", self.tick_hz, tick_hz());
+            tick_hz()
+        };
+        Duration::from_secs_f64(1.0 / hz)
+    }
+}
+
+/// Whether `hz` can be used as a tick rate
+fn valid_tick_hz(hz: f64) -> bool {
+    hz > 0.0 && hz.is_finite()
+}
+
+/// Limits on the connections the server accepts
+#[derive(Resource, Clone, Debug)]
+pub struct ConnectionLimits {
+    /// Connection requests arriving once this many clients are connected are refused by
+    /// the [`ConnectionGate`]
+    pub max_clients: usize,
+    /// Clients that sent no message for this long are disconnected, see [`ClientTimedOut`]
+    pub idle_timeout: Duration,
+    /// How long the entity of a disconnected client is kept, so that it gets the same entity
+    /// back if it reconnects in time
+    pub reconnect_grace: Duration,
+    /// How long the `ComponentA` of a despawned entity is remembered after that, so that
+    /// its client gets it back in a new entity if it reconnects
+    pub forget_after: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_clients: 32,
+            idle_timeout: Duration::from_secs(30),
+            reconnect_grace: Duration::from_secs(10),
+            forget_after: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// When each connected client last sent us something, as elapsed [`Time`]. Clients ping
+/// every second, so a working client is never idle for long
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ClientActivity(pub HashMap<ClientId, Duration>);
+
+/// Sent when a client is disconnected for staying silent longer than the idle timeout
+#[derive(Event, Debug, Clone)]
+pub struct ClientTimedOut {
+    pub client_id: ClientId,
+}
+
+/// Bandwidth used by the server, updated every tick by [`update_net_metrics`].
+///
+/// The byte and packet counts are cumulative over every transport. Lightyear only keeps
+/// those per transport, so the per-client breakdown counts the messages received from
+/// each client instead, with their serialized size.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct NetMetrics {
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub packets_sent: usize,
+    pub packets_received: usize,
+    /// Packets sent during the last tick
+    pub packets_sent_per_tick: usize,
+    /// Packets received during the last tick
+    pub packets_received_per_tick: usize,
+    pub clients: HashMap<ClientId, ClientMetrics>,
+}
+
+/// What a single client sent to the server, and the messages the server sent to it alone
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientMetrics {
+    pub messages_received: usize,
+    pub message_bytes_received: u64,
+    pub message_bytes_sent: u64,
+    /// As last reported by the client in its [`Ping`]
+    pub rtt: Duration,
+}
+
+/// While set, [`record_stats`] appends the [`NetMetrics`] to [`STATS_CSV_PATH`] every second
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct RecordStats(pub bool);
+
+/// Where [`record_stats`] writes, one `tick,client_id,bytes_sent,bytes_recv,rtt_ms` row per
+/// client and second.
+///
+/// The per-client bytes only count messages, as lightyear keeps the byte counts per
+/// transport. So every second also gets an `all` row with the transport totals, which
+/// include the replication traffic and are what to compare between relevance modes.
+pub const STATS_CSV_PATH: &str = "stats.csv";
+
+/// How often [`NetMetrics`] is logged, `None` to never log it
+#[derive(Resource, Clone, Debug, Default)]
+pub struct NetMetricsConfig {
+    pub log_interval: Option<Duration>,
+}
+
+impl NetMetricsConfig {
+    /// Log the metrics every second
+    pub fn every_second() -> Self {
+        Self {
+            log_interval: Some(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// The clients currently connected.
+///
+/// Kept up to date by [`track_connected_clients`] from the connect and disconnect events.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ConnectedClients(HashSet<ClientId>);
+
+impl ConnectedClients {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, client_id: ClientId) -> bool {
+        self.0.contains(&client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+/// Where a client connects from.
+///
+/// The netcode server of lightyear 0.18 knows the address of each client (`client_addr`),
+/// but it sits behind a `pub(crate)` field of the `netcode::Server` connection, so remote
+/// clients are all [`ClientAddress::Unavailable`] for now.
+// TODO: record the socket address of remote clients once lightyear exposes `client_addr`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAddress {
+    /// The local client of a host server
+    Local,
+    /// Any remote client, see above
+    Unavailable,
+}
+
+/// The address of every connected client, recorded by [`record_client_addresses`]
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ClientAddresses(pub HashMap<ClientId, ClientAddress>);
+
+/// Clients the server refuses, by id. Loaded from `path` at startup, and saved back by
+/// [`BanList::ban_client`] and [`BanList::unban_client`]. The [`ConnectionGate`] refuses
+/// their connection requests, a client banned while connected stays until it leaves
+#[derive(Resource, Clone, Debug)]
+pub struct BanList {
+    pub path: PathBuf,
+    pub clients: HashSet<ClientId>,
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("bans.ron"),
+            clients: HashSet::default(),
+        }
+    }
+}
+
+/// How the [`BanList`] is written to disk
+#[derive(Serialize, Deserialize, Default)]
+struct BanFile {
+    clients: Vec<ClientId>,
+}
+
+impl BanList {
+    /// Read the bans saved at `path`, none if the file doesn't exist
+    pub fn load(path: PathBuf) -> Self {
+        let file: BanFile = match std::fs::read_to_string(&path) {
+            Ok(text) => bevy::scene::ron::from_str(&text).unwrap_or_else(|err| {
+                warn!("Ignoring invalid ban list {:?}: {}", path, err);
+                BanFile::default()
+            }),
+            Err(_) => BanFile::default(),
+        };
+        Self {
+            path,
+            clients: file.clients.into_iter().collect(),
+        }
+    }
+
+    pub fn is_banned(&self, client_id: ClientId) -> bool {
+        self.clients.contains(&client_id)
+    }
+
+    pub fn ban_client(&mut self, client_id: ClientId) {
+        if self.clients.insert(client_id) {
+            self.save();
+        }
+    }
+
+    pub fn unban_client(&mut self, client_id: ClientId) {
+        if self.clients.remove(&client_id) {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let file = BanFile {
+            clients: self.clients.iter().copied().collect(),
+        };
+        let result = bevy::scene::ron::ser::to_string_pretty(&file, default())
+            .map_err(|err| err.to_string())
+            .and_then(|text| std::fs::write(&self.path, text).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            error!("Failed to save the ban list to {:?}: {}", self.path, err);
+        }
+    }
+}
+
+/// Sent when the server refuses a client
+#[derive(Event, Debug, Clone)]
+pub struct ConnectionRejected {
+    pub client_id: ClientId,
+    pub reason: DeniedReason,
+}
+
+/// How long a client let through by the [`ConnectionGate`] may take to finish the handshake.
+/// Until then it holds a slot, so that requests arriving together can't overfill the server
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Decides which connection requests Netcode accepts, before the client gets a connect
+/// token through. A clone is installed on every transport, and the systems keep it in sync
+/// with [`ConnectedClients`] and [`ConnectionLimits`]
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ConnectionGate {
+    /// How many clients are connected, the length of [`ConnectedClients`]
+    connected: Arc<AtomicUsize>,
+    max_clients: Arc<AtomicUsize>,
+    /// The clients of the [`BanList`]
+    banned: Arc<RwLock<HashSet<ClientId>>>,
+    /// The clients let through that haven't connected yet, and when they were let through
+    pending: Arc<Mutex<HashMap<ClientId, Instant>>>,
+    /// The requests refused since [`report_refused_requests`] last ran
+    refused: Arc<Mutex<Vec<ConnectionRejected>>>,
+}
+
+impl ConnectionGate {
+    fn new(limits: &ConnectionLimits, bans: &BanList) -> Self {
+        let gate = Self::default();
+        gate.max_clients
+            .store(limits.max_clients, Ordering::Relaxed);
+        *gate.banned.write().unwrap() = bans.clients.clone();
+        gate
+    }
+
+    /// Record the clients now connected, which no longer hold a pending slot
+    fn set_connected(&self, connected: &ConnectedClients) {
+        self.connected.store(connected.len(), Ordering::Relaxed);
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|client_id, _| !connected.contains(*client_id));
+    }
+
+    fn refuse(&self, client_id: ClientId, reason: DeniedReason) -> Option<DeniedReason> {
+        warn!("Refusing the connection of {:?}: {:?}", client_id, reason);
+        self.refused.lock().unwrap().push(ConnectionRejected {
+            client_id,
+            reason: reason.clone(),
+        });
+        Some(reason)
+    }
+}
+
+impl ConnectionRequestHandler for ConnectionGate {
+    fn handle_request(&self, client_id: ClientId) -> Option<DeniedReason> {
+        if self.banned.read().unwrap().contains(&client_id) {
+            return self.refuse(client_id, DeniedReason::Banned);
+        }
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, since| now.duration_since(*since) < HANDSHAKE_TIMEOUT);
+        // clients resend their request until the server answers
+        if pending.contains_key(&client_id) {
+            return None;
+        }
+        let taken = self.connected.load(Ordering::Relaxed) + pending.len();
+        if taken >= self.max_clients.load(Ordering::Relaxed) {
+            return self.refuse(client_id, DeniedReason::ServerFull);
+        }
+        pending.insert(client_id, now);
+        None
+    }
+}
+
+/// Sent for each client that connected, the banned ones and the ones past the limit were
+/// refused by the [`ConnectionGate`] before. The systems setting up a new client read this
+/// rather than [`ServerConnectEvent`], so that the connection bookkeeping is done first
+#[derive(Event, Debug, Clone)]
+pub struct ClientAccepted {
+    pub client_id: ClientId,
+}
+
+/// Here we create the lightyear [`ServerPlugins`]
+fn build_server_plugin(
+    transports: Vec<ServerTransport>,
+    mode: Mode,
+    settings: &ServerSettings,
+    gate: &ConnectionGate,
+) -> ServerPlugins {
+    let netcode = netcode_config();
+    let net = transports
+        .into_iter()
+        .map(|transport| {
+            // The IoConfig will specify the transport to use.
+            let io = IoConfig {
+                transport,
+                conditioner: link_conditioner(),
+                ..default()
+            };
+            // The NetConfig specifies how we establish a connection with the server.
+            // We can use either Steam (in which case we will use steam sockets and there is no need to specify
+            // our own io) or Netcode (in which case we need to specify our own io).
+            let mut net = NetConfig::Netcode {
+                io,
+                config: netcode.clone(),
+            };
+            net.set_connection_request_handler(Arc::new(gate.clone()));
+            net
+        })
+        .collect();
+    // part of the config needs to be shared between the client and server
+    let mut shared = shared_config_with_mode(mode);
+    shared.tick.tick_duration = settings.tick_duration();
+    shared.server_replication_send_interval = settings.replication_interval();
+    let config = ServerConfig {
+        shared,
+        // we can specify multiple net configs here, and the server will listen on all of them
+        // at the same time, one per entry of ServerTransports
+        net,
+        replication: ReplicationConfig {
+            // we will send updates to the clients every 100ms by default
+            send_interval: settings.replication_interval(),
+            ..default()
+        },
         ..default()
     };
-    // The NetConfig specifies how we establish a connection with the server.
-    // We can use either Steam (in which case we will use steam sockets and there is no need to specify
-    // our own io) or Netcode (in which case we need to specify our own io).
-    let net_config = NetConfig::Netcode {
-        io,
-        config: NetcodeConfig::default(),
+    ServerPlugins::new(config)
+}
+
+impl Plugin for ExampleServerPlugin {
+    fn build(&self, app: &mut App) {
+        // Asset sources have to be registered before the AssetPlugin
+        #[cfg(target_arch = "wasm32")]
+        if let Some((base, _)) = app
+            .world()
+            .get_resource::<SceneLoadConfig>()
+            .cloned()
+            .unwrap_or_default()
+            .url
+            .as_deref()
+            .and_then(|url| url.rsplit_once('/'))
+        {
+            let base = base.to_string();
+            app.register_asset_source(
+                SCENE_URL_SOURCE,
+                bevy::asset::io::AssetSource::build().with_reader(move || {
+                    Box::new(bevy::asset::io::wasm::HttpWasmAssetReader::new(
+                        base.clone(),
+                    ))
+                }),
+            );
+        }
+        let headless = cfg!(feature = "headless") || app.world().contains_resource::<Headless>();
+        #[cfg(not(feature = "headless"))]
+        if !headless {
+            app.add_plugins(DefaultPlugins.set(log_plugin()));
+        }
+        // Only what the server logic needs: no window, no renderer, no GPU
+        if headless {
+            app.add_plugins((
+                MinimalPlugins,
+                StatesPlugin,
+                log_plugin(),
+                TransformPlugin,
+                HierarchyPlugin,
+                bevy::input::InputPlugin,
+                AssetPlugin::default(),
+                bevy::scene::ScenePlugin,
+            ));
+        }
+
+        let settings = app
+            .world()
+            .get_resource::<ServerSettings>()
+            .cloned()
+            .unwrap_or_else(ServerSettings::load);
+        app.insert_resource(settings.clone());
+
+        // add lightyear plugins
+        let transport_kinds = app
+            .world()
+            .get_resource::<ServerTransports>()
+            .cloned()
+            .unwrap_or_else(|| ServerTransports::udp([settings.addr, SECONDARY_SERVER_ADDR]));
+        let mut transports = Vec::new();
+        for kind in &transport_kinds.0 {
+            let (transport, digest) = server_transport(kind);
+            if let Some(digest) = digest {
+                app.insert_resource(digest);
+            }
+            transports.push(transport);
+        }
+        let mode = if app.world().contains_resource::<HostServerMode>() {
+            Mode::HostServer
+        } else {
+            Mode::Separate
+        };
+        // Keep track of the connected clients and turn away the ones past the limit
+        if !app.world().contains_resource::<ConnectionLimits>() {
+            app.insert_resource(ConnectionLimits {
+                max_clients: settings.max_clients,
+                ..default()
+            });
+        }
+        let bans_path = app
+            .world()
+            .get_resource::<BanList>()
+            .map_or(BanList::default().path, |bans| bans.path.clone());
+        let bans = BanList::load(bans_path);
+        let gate = ConnectionGate::new(app.world().resource::<ConnectionLimits>(), &bans);
+        app.add_plugins(build_server_plugin(transports, mode, &settings, &gate));
+        app.insert_resource(gate);
+        app.insert_resource(bans);
+        #[cfg(all(feature = "inspector", not(feature = "headless")))]
+        if !headless {
+            app.add_plugins(WorldInspectorPlugin::new());
+            app.add_systems(Update, client_panel);
+        }
+
+        // add our shared plugin containing the protocol + other shared behaviour
+        app.add_plugins(SharedPlugin);
+
+        // add our server-specific logic. Here we will just start listening for incoming connections
+        app.add_systems(Startup, start_server);
+
+        app.init_resource::<ConnectedClients>();
+        app.init_resource::<ClientAddresses>();
+        app.add_event::<ConnectionRejected>();
+        app.add_event::<ClientAccepted>();
+        app.add_systems(
+            PreUpdate,
+            (
+                track_connected_clients,
+                record_client_addresses,
+                report_refused_requests,
+                accept_clients,
+            )
+                .chain()
+                .after(MainSet::EmitEvents),
+        );
+        app.add_systems(PreUpdate, update_connection_gate.before(MainSet::Receive));
+        app.add_systems(Update, check_client_tick_rate);
+        app.init_resource::<ClientActivity>();
+        app.add_event::<ClientTimedOut>();
+        app.add_systems(
+            Update,
+            (track_client_activity, disconnect_idle_clients).chain(),
+        );
+
+        // Measure the bandwidth
+        app.init_resource::<NetMetrics>();
+        app.init_resource::<NetMetricsConfig>();
+        app.add_systems(FixedLast, update_net_metrics);
+        app.add_systems(Update, (count_client_messages, log_net_metrics));
+        app.init_resource::<RecordStats>();
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_systems(Update, record_stats.before(stop_server));
+
+        #[cfg(not(feature = "headless"))]
+        if !headless {
+            app.add_systems(Startup, spawn_camera);
+            app.add_systems(
+                Update,
+                (
+                    spawn_carrier_labels,
+                    draw_relevance_gizmos,
+                    move_carrier_labels,
+                ),
+            );
+        }
+        #[cfg(all(feature = "orbit_camera", not(feature = "headless")))]
+        if !headless && !app.is_plugin_added::<crate::camera::OrbitCameraPlugin>() {
+            app.add_plugins(crate::camera::OrbitCameraPlugin);
+        }
+
+        // Run this if you want to make a new scene
+        app.init_resource::<SceneSaveConfig>();
+        app.init_resource::<PendingSceneSaves>();
+        app.init_resource::<SavedSceneHashes>();
+        app.add_event::<SceneSaved>();
+        app.add_event::<SceneUnchanged>();
+        app.add_event::<SceneSaveFailed>();
+        app.add_systems(
+            Update,
+            (
+                create_save_scene,
+                save_scene_on_key,
+                autosave_scene,
+                poll_scene_saves,
+            )
+                .chain(),
+        );
+        app.init_resource::<AutosaveConfig>();
+
+        // Run this to load scene
+        app.init_resource::<SceneLoadConfig>();
+        app.add_systems(Startup, spawn_scene_if_exists);
+        app.add_event::<SceneValidationReport>();
+        app.add_event::<SceneLoadFailed>();
+        app.add_event::<UnloadScene>();
+        app.add_observer(tag_scene_entities);
+        app.add_systems(Update, unload_scene);
+        #[cfg(feature = "hot_reload")]
+        {
+            app.add_event::<SceneReloaded>();
+            app.add_systems(Update, reload_modified_scene);
+        }
+        app.add_systems(Update, (validate_loaded_scenes, despawn_failed_scene_roots));
+
+        // Find the entity of a client without scanning every carrier
+        app.init_resource::<CarrierIndex>();
+        // and give out the NetIds
+        app.init_resource::<NetIdAllocator>();
+        app.add_systems(PreUpdate, update_carrier_index);
+
+        // Replicate
+        app.init_resource::<LobbyConfig>();
+        app.init_resource::<RoomRegistry>();
+        app.init_resource::<RelevanceMode>();
+        app.init_resource::<ReplicationTargetConfig>();
+        app.add_event::<RoomFull>();
+        app.init_resource::<RoomIntervals>();
+        app.add_event::<TransferClientRequest>();
+        app.add_event::<ClientTransferred>();
+        app.init_resource::<Matchmaker>();
+        app.add_event::<MatchStarted>();
+        app.add_systems(
+            Update,
+            (
+                reattach_reconnected_players,
+                restore_rooms_on_connect,
+                add_replicate,
+                matchmake,
+                handle_transfer_requests,
+                track_rooms,
+            )
+                .chain(),
+        );
+        app.add_systems(
+            PostUpdate,
+            prioritize_own_entities
+                .after(ReplicationSet::BufferEntityUpdates)
+                .before(ReplicationSet::AfterBuffer),
+        );
+
+        // Remember the rooms across restarts
+        app.init_resource::<RoomPersistence>();
+        app.init_resource::<PersistedRooms>();
+        app.add_systems(Startup, load_persisted_rooms);
+
+        // Chat
+        app.add_systems(Update, broadcast_chat);
+
+        // Give the clients the scene when they connect and when they ask for it
+        app.init_resource::<NextPayloadId>();
+        app.init_resource::<SceneEntityHashes>();
+        app.init_resource::<SentScenes>();
+        app.add_systems(Update, (send_scene_snapshots, answer_scene_requests));
+        app.add_systems(Update, send_scene_deltas);
+
+        // Names picked by the clients
+        app.add_systems(Update, apply_rename_requests);
+
+        // Mirror the shared resources to the clients
+        app.add_systems(Update, send_game_score);
+
+        // Latency
+        app.add_systems(Update, answer_pings);
+
+        // Keep the replicated values moving
+        app.init_resource::<IncrementConfig>();
+        app.init_resource::<PausedRooms>();
+        app.add_systems(Update, increment_component_a);
+
+        // Move the players around, one buffered input per tick
+        app.init_resource::<InputBuffer>();
+        app.add_systems(Update, (buffer_player_input, update_distance_relevance));
+        app.add_systems(FixedUpdate, apply_player_input);
+
+        // Debug room membership
+        app.add_systems(
+            Update,
+            dump_rooms.run_if(bevy::input::common_conditions::input_just_pressed(
+                DUMP_ROOMS_KEY,
+            )),
+        );
+
+        // Clean up after clients that left
+        app.add_systems(
+            Update,
+            (
+                cleanup_rooms_on_disconnect,
+                despawn_disconnected_players,
+                despawn_expired_players,
+            ),
+        );
+        app.init_resource::<DespawnedPlayers>();
+
+        // Freeze replication while debugging
+        app.init_resource::<ReplicationPaused>();
+        app.add_systems(
+            Update,
+            (
+                toggle_replication_pause.run_if(
+                    bevy::input::common_conditions::input_just_pressed(PAUSE_REPLICATION_KEY),
+                ),
+                apply_replication_pause,
+            )
+                .chain()
+                .after(add_replicate),
+        );
+
+        // Moderation
+        app.add_event::<KickClient>();
+        app.add_systems(Update, (kick_last_client, kick_clients).chain());
+
+        // Client authority
+        app.add_event::<TransferAuthority>();
+        app.add_systems(
+            Update,
+            (give_authority_to_carriers, transfer_authority).chain(),
+        );
+
+        // Stop cleanly on Ctrl-C or on the shutdown key
+        let signal = ShutdownSignal::default();
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let flag = signal.0.clone();
+            if let Err(err) = ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst)) {
+                warn!("Failed to install the Ctrl-C handler: {}", err);
+            }
+        }
+        app.insert_resource(signal);
+        app.add_event::<ShutdownRequest>();
+        app.add_systems(
+            Update,
+            (
+                request_shutdown_on_signal,
+                request_shutdown.run_if(bevy::input::common_conditions::input_just_pressed(
+                    SHUTDOWN_KEY,
+                )),
+                persist_rooms,
+                stop_server,
+            )
+                .chain(),
+        );
+    }
+}
+
+/// Start the server
+fn start_server(mut commands: Commands) {
+    commands.start_server();
+}
+
+/// Keep [`ConnectedClients`] up to date.
+///
+/// The connect and disconnect events are read separately, so when both show up for the same
+/// client in one frame we can't tell their order from the events. What the client was before
+/// tells: a client that was connected left and came back, any other one came and left.
+fn track_connected_clients(
+    gate: Res<ConnectionGate>,
+    mut connected: ResMut<ConnectedClients>,
+    mut connect_events: EventReader<ServerConnectEvent>,
+    mut disconnect_events: EventReader<ServerDisconnectEvent>,
+) {
+    let connects: HashSet<ClientId> = connect_events.read().map(|e| e.client_id).collect();
+    let disconnects: HashSet<ClientId> = disconnect_events.read().map(|e| e.client_id).collect();
+    if connects.is_empty() && disconnects.is_empty() {
+        return;
+    }
+    for client_id in &disconnects {
+        let reconnected = connects.contains(client_id) && connected.0.contains(client_id);
+        if !reconnected {
+            connected.0.remove(client_id);
+        }
+    }
+    for client_id in &connects {
+        let left = disconnects.contains(client_id) && !connected.0.contains(client_id);
+        if !left {
+            connected.0.insert(*client_id);
+        }
+    }
+    gate.set_connected(&connected);
+    info!("{} client(s) connected", connected.len());
+}
+
+/// Record where each new client connects from, only the local client of a host server
+/// gets a known address for now (see [`ClientAddress`])
+fn record_client_addresses(
+    mut addresses: ResMut<ClientAddresses>,
+    mut connect_events: EventReader<ServerConnectEvent>,
+    mut disconnect_events: EventReader<ServerDisconnectEvent>,
+) {
+    for event in disconnect_events.read() {
+        addresses.0.remove(&event.client_id);
+    }
+    for event in connect_events.read() {
+        let client_id = event.client_id;
+        let address = if matches!(client_id, ClientId::Local(_)) {
+            ClientAddress::Local
+        } else {
+            ClientAddress::Unavailable
+        };
+        info!("{:?} connected from {:?}", client_id, address);
+        addresses.0.insert(client_id, address);
+    }
+}
+
+/// Pass [`ConnectionLimits::max_clients`] and the [`BanList`] on to the [`ConnectionGate`]
+/// when they change
+fn update_connection_gate(
+    gate: Res<ConnectionGate>,
+    limits: Res<ConnectionLimits>,
+    bans: Res<BanList>,
+) {
+    if limits.is_changed() {
+        gate.max_clients
+            .store(limits.max_clients, Ordering::Relaxed);
+    }
+    if bans.is_changed() {
+        *gate.banned.write().unwrap() = bans.clients.clone();
+    }
+}
+
+/// Send a [`ConnectionRejected`] for every connection request the [`ConnectionGate`]
+/// refused. Those clients never connect, so nothing else hears of them
+fn report_refused_requests(
+    gate: Res<ConnectionGate>,
+    mut rejected_writer: EventWriter<ConnectionRejected>,
+) {
+    let refused = std::mem::take(&mut *gate.refused.lock().unwrap());
+    rejected_writer.send_batch(refused);
+}
+
+/// Send a [`ClientAccepted`] for every client that connected this frame, once
+/// [`ConnectedClients`] and [`ClientAddresses`] know about it
+fn accept_clients(
+    mut connect_events: EventReader<ServerConnectEvent>,
+    mut accepted_writer: EventWriter<ClientAccepted>,
+) {
+    accepted_writer.send_batch(connect_events.read().map(|event| ClientAccepted {
+        client_id: event.client_id,
+    }));
+}
+
+/// Remember when each client last connected or sent a message
+fn track_client_activity(
+    time: Res<Time>,
+    mut activity: ResMut<ClientActivity>,
+    mut accepted_events: EventReader<ClientAccepted>,
+    mut disconnect_events: EventReader<ServerDisconnectEvent>,
+    mut hello_events: EventReader<ServerMessageEvent<ClientHello>>,
+    mut input_events: EventReader<ServerMessageEvent<PlayerInput>>,
+    mut ping_events: EventReader<ServerMessageEvent<Ping>>,
+    mut chat_events: EventReader<ServerMessageEvent<ChatMessage>>,
+) {
+    let now = time.elapsed();
+    let active = accepted_events
+        .read()
+        .map(|event| event.client_id)
+        .chain(hello_events.read().map(|event| *event.context()))
+        .chain(input_events.read().map(|event| *event.context()))
+        .chain(ping_events.read().map(|event| *event.context()))
+        .chain(chat_events.read().map(|event| *event.context()));
+    for client_id in active {
+        // the local client of a host server can't go silent
+        if !matches!(client_id, ClientId::Local(_)) {
+            activity.0.insert(client_id, now);
+        }
+    }
+    for event in disconnect_events.read() {
+        activity.0.remove(&event.client_id);
+    }
+}
+
+/// Disconnect the clients silent for longer than [`ConnectionLimits::idle_timeout`]. This
+/// frees the slots of clients that crashed without disconnecting
+fn disconnect_idle_clients(
+    time: Res<Time>,
+    limits: Res<ConnectionLimits>,
+    mut activity: ResMut<ClientActivity>,
+    mut connections: ResMut<ServerConnections>,
+    mut timed_out_writer: EventWriter<ClientTimedOut>,
+) {
+    let now = time.elapsed();
+    activity.0.retain(|&client_id, last_seen| {
+        if now.saturating_sub(*last_seen) <= limits.idle_timeout {
+            return true;
+        }
+        warn!(
+            "{:?} was idle for more than {:?}, disconnecting it",
+            client_id, limits.idle_timeout
+        );
+        if let Err(err) = connections.disconnect(client_id) {
+            error!("Failed to disconnect {:?}: {:?}", client_id, err);
+        }
+        timed_out_writer.send(ClientTimedOut { client_id });
+        false
+    });
+}
+
+/// Warn about clients running at a different tick rate than ours, which breaks tick sync
+fn check_client_tick_rate(
+    config: Res<ServerConfig>,
+    mut hello_events: EventReader<ServerMessageEvent<ClientHello>>,
+) {
+    let server_tick = config.shared.tick.tick_duration;
+    for event in hello_events.read() {
+        let client_tick = event.message().tick_duration;
+        if client_tick != server_tick {
+            warn!(
+                "{:?} ticks at {:.1}Hz but the server ticks at {:.1}Hz, set {} to the same value on both",
+                *event.context(),
+                1.0 / client_tick.as_secs_f64(),
+                1.0 / server_tick.as_secs_f64(),
+                TICK_HZ_ENV
+            );
+        } else {
+            info!(
+                "{:?} ticks at the same rate as the server",
+                *event.context()
+            );
+        }
+    }
+}
+
+fn spawn_camera(mut commands: Commands) {
+    let camera = commands.spawn(Camera3d::default()).id();
+    #[cfg(feature = "orbit_camera")]
+    commands
+        .entity(camera)
+        .insert(crate::camera::OrbitCamera::default());
+}
+
+/// Screen text naming the client carrying the entity, kept next to it by
+/// [`move_carrier_labels`]
+#[cfg(not(feature = "headless"))]
+#[derive(Component)]
+struct CarrierLabel(Entity);
+
+/// The color of the lines drawn to the entities of `client_id`: the color of its room, the
+/// one it was last transferred to, or of the client itself when rooms aren't used
+#[cfg(not(feature = "headless"))]
+fn relevance_color(
+    client_id: ClientId,
+    lobby_config: &LobbyConfig,
+    persisted: &PersistedRooms,
+    relevance_mode: RelevanceMode,
+) -> Color {
+    if relevance_mode == RelevanceMode::Rooms && lobby_config.use_rooms {
+        hashed_color(persisted.room_of(client_id, lobby_config).0)
+    } else {
+        hashed_color(client_id.to_bits())
+    }
+}
+
+/// Draw a line from the origin to every replicated player, colored after its room, so that
+/// who shares a room with whom can be seen at a glance
+#[cfg(not(feature = "headless"))]
+fn draw_relevance_gizmos(
+    mut gizmos: Gizmos,
+    lobby_config: Res<LobbyConfig>,
+    persisted: Res<PersistedRooms>,
+    relevance_mode: Res<RelevanceMode>,
+    players: Query<(&CarrierId, &NetPosition), With<Replicated>>,
+) {
+    gizmos.sphere(Isometry3d::IDENTITY, 0.2, Color::WHITE);
+    for (carrier_id, position) in players.iter() {
+        let color = relevance_color(carrier_id.0, &lobby_config, &persisted, *relevance_mode);
+        gizmos.line(Vec3::ZERO, position.0, color);
+    }
+}
+
+#[cfg(not(feature = "headless"))]
+fn spawn_carrier_labels(
+    mut commands: Commands,
+    lobby_config: Res<LobbyConfig>,
+    persisted: Res<PersistedRooms>,
+    relevance_mode: Res<RelevanceMode>,
+    players: Query<(Entity, &CarrierId), Added<Replicated>>,
+) {
+    for (entity, carrier_id) in players.iter() {
+        let client_id = carrier_id.0;
+        commands.spawn((
+            Text::new(format!("{:?}", client_id)),
+            TextColor(relevance_color(
+                client_id,
+                &lobby_config,
+                &persisted,
+                *relevance_mode,
+            )),
+            Node {
+                position_type: PositionType::Absolute,
+                ..default()
+            },
+            CarrierLabel(entity),
+        ));
+    }
+}
+
+/// Keep every label over its entity and in the color of its room, and remove the labels of
+/// the despawned ones
+#[cfg(not(feature = "headless"))]
+fn move_carrier_labels(
+    mut commands: Commands,
+    lobby_config: Res<LobbyConfig>,
+    persisted: Res<PersistedRooms>,
+    relevance_mode: Res<RelevanceMode>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    players: Query<(&NetPosition, &CarrierId)>,
+    mut labels: Query<(
+        Entity,
+        &CarrierLabel,
+        &mut Node,
+        &mut Visibility,
+        &mut TextColor,
+    )>,
+) {
+    let Ok((camera, camera_transform)) = cameras.get_single() else {
+        return;
+    };
+    for (label, CarrierLabel(target), mut node, mut visibility, mut color) in labels.iter_mut() {
+        let Ok((position, carrier_id)) = players.get(*target) else {
+            commands.entity(label).despawn_recursive();
+            continue;
+        };
+        // the room changes with transfers
+        let room_color = relevance_color(carrier_id.0, &lobby_config, &persisted, *relevance_mode);
+        if color.0 != room_color {
+            color.0 = room_color;
+        }
+        match camera.world_to_viewport(camera_transform, position.0) {
+            Ok(screen) => {
+                node.left = Val::Px(screen.x);
+                node.top = Val::Px(screen.y);
+                *visibility = Visibility::Inherited;
+            }
+            // behind the camera
+            Err(_) => *visibility = Visibility::Hidden,
+        }
+    }
+}
+
+/// Read the totals of every transport into [`NetMetrics`]
+fn update_net_metrics(connections: Res<ServerConnections>, mut metrics: ResMut<NetMetrics>) {
+    let (mut bytes_sent, mut bytes_received) = (0, 0);
+    let (mut packets_sent, mut packets_received) = (0, 0);
+    for server in &connections.servers {
+        if let Some(io) = server.io() {
+            let stats = io.stats();
+            bytes_sent += stats.bytes_sent;
+            bytes_received += stats.bytes_received;
+            packets_sent += stats.packets_sent;
+            packets_received += stats.packets_received;
+        }
+    }
+    metrics.packets_sent_per_tick = packets_sent.saturating_sub(metrics.packets_sent);
+    metrics.packets_received_per_tick = packets_received.saturating_sub(metrics.packets_received);
+    metrics.bytes_sent = bytes_sent;
+    metrics.bytes_received = bytes_received;
+    metrics.packets_sent = packets_sent;
+    metrics.packets_received = packets_received;
+}
+
+/// Count the messages each client sends, and forget the clients that left
+fn count_client_messages(
+    mut metrics: ResMut<NetMetrics>,
+    mut hello_events: EventReader<ServerMessageEvent<ClientHello>>,
+    mut input_events: EventReader<ServerMessageEvent<PlayerInput>>,
+    mut disconnect_events: EventReader<ServerDisconnectEvent>,
+) {
+    let mut record = |client_id: ClientId, bytes: u64| {
+        let client = metrics.clients.entry(client_id).or_default();
+        client.messages_received += 1;
+        client.message_bytes_received += bytes;
     };
-    let config = ServerConfig {
-        // part of the config needs to be shared between the client and server
-        shared: shared_config(),
-        // we can specify multiple net configs here, and the server will listen on all of them
-        // at the same time. Here we will only use one
-        net: vec![net_config],
-        replication: ReplicationConfig {
-            // we will send updates to the clients every 100ms
-            send_interval: SERVER_REPLICATION_INTERVAL,
-            ..default()
-        },
-        ..default()
+    for event in hello_events.read() {
+        record(
+            *event.context(),
+            bincode::serialized_size(event.message()).unwrap_or_default(),
+        );
+    }
+    for event in input_events.read() {
+        record(
+            *event.context(),
+            bincode::serialized_size(event.message()).unwrap_or_default(),
+        );
+    }
+    for event in disconnect_events.read() {
+        metrics.clients.remove(&event.client_id);
+    }
+}
+
+fn log_net_metrics(
+    time: Res<Time>,
+    config: Res<NetMetricsConfig>,
+    metrics: Res<NetMetrics>,
+    mut timer: Local<Option<Timer>>,
+) {
+    let Some(interval) = config.log_interval else {
+        return;
     };
-    ServerPlugins::new(config)
+    let timer = timer.get_or_insert_with(|| Timer::new(interval, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    info!(
+        "Sent {} bytes ({} packets), received {} bytes ({} packets), {}/{} packets last tick",
+        metrics.bytes_sent,
+        metrics.packets_sent,
+        metrics.bytes_received,
+        metrics.packets_received,
+        metrics.packets_sent_per_tick,
+        metrics.packets_received_per_tick
+    );
+    for (client_id, client) in &metrics.clients {
+        info!(
+            "{:?} sent {} messages ({} bytes)",
+            client_id, client.messages_received, client.message_bytes_received
+        );
+    }
+}
+
+// Here we create a very simple dynamic scene asset
+//
+// The scene world is kept between connects rather than rebuilt for each of them: its
+// components, archetypes and tables are only allocated for the first client, the
+// following ones just clear and respawn a single entity.
+fn create_save_scene(
+    app_type_registry: Res<AppTypeRegistry>,
+    save_config: Res<SceneSaveConfig>,
+    mut pending_saves: ResMut<PendingSceneSaves>,
+    mut saved_hashes: ResMut<SavedSceneHashes>,
+    mut event_reader: EventReader<ClientAccepted>,
+    mut unchanged_writer: EventWriter<SceneUnchanged>,
+    mut scene_world: Local<Option<World>>,
+) {
+    for event in event_reader.read() {
+        let client_id = event.client_id;
+        let scene_world = scene_world.get_or_insert_with(|| {
+            let mut scene_world = World::new();
+            scene_world.insert_resource(app_type_registry.clone());
+            scene_world
+        });
+        fill_scene_world(scene_world, client_id);
+        info!("Resulting scene world {:?}", scene_world);
+        let data = match save_config.format {
+            // `serialize_current_scene` pretty prints, as `DynamicScene::serialize` does
+            SceneFormat::Ron if save_config.pretty => {
+                serialize_current_scene(scene_world).map(String::into_bytes)
+            }
+            format => {
+                save_current_scene(scene_world, &app_type_registry, format, save_config.pretty)
+            }
+        };
+        match data {
+            Ok(data) => {
+                // bursts of connects keep producing the same scene, skip writing it again
+                let path = save_config.path_for(Some(client_id));
+                let hash = scene_hash(&data);
+                if saved_hashes.0.get(&path) == Some(&hash) {
+                    info!("Scene at {:?} is unchanged, not saving it", path);
+                    unchanged_writer.send(SceneUnchanged { client_id, path });
+                    continue;
+                }
+                pending_saves.queue(
+                    &mut saved_hashes,
+                    Some(client_id),
+                    path,
+                    data,
+                    save_config.keep_backups(),
+                );
+            }
+            Err(err) => error!(
+                "Failed to serialize scene for client {:?}: {}",
+                client_id, err
+            ),
+        }
+    }
+}
+
+/// Save the entities of the server world when the save key is pressed
+fn save_scene_on_key(world: &mut World) {
+    let save_key = world.resource::<SceneSaveConfig>().save_key;
+    let Some(keys) = world.get_resource::<ButtonInput<KeyCode>>() else {
+        return;
+    };
+    if !keys.just_pressed(save_key) {
+        return;
+    }
+    let save_config = world.resource::<SceneSaveConfig>().clone();
+    let app_type_registry = world.resource::<AppTypeRegistry>().clone();
+    match save_current_scene(
+        world,
+        &app_type_registry,
+        save_config.format,
+        save_config.pretty,
+    ) {
+        Ok(data) => {
+            info!("Saving scene to {:?}", save_config.path_for(None));
+            world.resource_scope(|world, mut saved_hashes: Mut<SavedSceneHashes>| {
+                world.resource_mut::<PendingSceneSaves>().queue(
+                    &mut saved_hashes,
+                    None,
+                    save_config.path_for(None),
+                    data,
+                    save_config.keep_backups(),
+                );
+            });
+        }
+        Err(err) => error!("Failed to serialize scene: {}", err),
+    }
+}
+
+/// Save the entities of the server world every [`AutosaveConfig::interval`]
+fn autosave_scene(world: &mut World, mut timer: Local<Option<Timer>>) {
+    let autosave = world.resource::<AutosaveConfig>().clone();
+    if !autosave.enabled {
+        return;
+    }
+    let timer = timer.get_or_insert_with(|| Timer::new(autosave.interval, TimerMode::Repeating));
+    timer.set_duration(autosave.interval);
+    if !timer.tick(world.resource::<Time>().delta()).just_finished() {
+        return;
+    }
+    let save_config = world.resource::<SceneSaveConfig>().clone();
+    let app_type_registry = world.resource::<AppTypeRegistry>().clone();
+    let tick = current_tick(world);
+    match save_current_scene(
+        world,
+        &app_type_registry,
+        save_config.format,
+        save_config.pretty,
+    ) {
+        Ok(mut data) => {
+            if save_config.format == SceneFormat::Ron {
+                let mut commented = format!("// autosave at server tick {}\n", tick.0).into_bytes();
+                commented.append(&mut data);
+                data = commented;
+            }
+            let path = save_config.autosave_path();
+            info!("Autosaving scene to {:?} at tick {:?}", path, tick);
+            world.resource_scope(|world, mut saved_hashes: Mut<SavedSceneHashes>| {
+                world.resource_mut::<PendingSceneSaves>().queue(
+                    &mut saved_hashes,
+                    None,
+                    path,
+                    data,
+                    None,
+                );
+            });
+        }
+        Err(err) => error!("Failed to serialize scene for the autosave: {}", err),
+    }
+}
+
+/// Build the small world that gets saved when `client_id` connects
+fn build_scene_world(app_type_registry: &AppTypeRegistry, client_id: ClientId) -> World {
+    // Grab registry just for serializaitopn
+    let mut scene_world = World::new();
+    scene_world.insert_resource(app_type_registry.clone());
+    fill_scene_world(&mut scene_world, client_id);
+    scene_world
+}
+
+/// Replace the entities of `scene_world` with the ones saved for `client_id`
+fn fill_scene_world(scene_world: &mut World, client_id: ClientId) {
+    scene_world.clear_entities();
+
+    // Component A being add
+    scene_world
+        .spawn(ComponentA(2))
+        .insert(ComponentB(0.5))
+        .insert(CarrierId(client_id))
+        .insert(Name::new("Replicated entity"))
+        .insert(FromScene);
+}
+
+/// Serialize the scene entities of `world`, the ones tagged [`FromScene`], and return them
+/// as RON instead of writing them to disk. [`create_save_scene`] writes what this returns
+/// for the scene world it builds, and the clients get it for the server world in a
+/// [`SceneSnapshot`].
+///
+/// `world` needs an [`AppTypeRegistry`]. This also works on WASM, where scenes can't be
+/// saved to the filesystem.
+pub fn serialize_current_scene(world: &mut World) -> Result<String, SceneSerializationError> {
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, With<FromScene>>()
+        .iter(world)
+        .collect();
+    let app_type_registry = world.resource::<AppTypeRegistry>();
+    extract_scene_entities(world, entities.into_iter())
+        .serialize(&app_type_registry.read())
+        .map_err(SceneSerializationError::Ron)
+}
+
+/// Serialize every entity of `world` that carries a [`ComponentA`], see [`extract_scene`].
+/// `pretty` only applies to [`SceneFormat::Ron`]
+pub fn save_current_scene(
+    world: &World,
+    registry: &AppTypeRegistry,
+    format: SceneFormat,
+    pretty: bool,
+) -> Result<Vec<u8>, SceneSerializationError> {
+    serialize_scene(&extract_scene(world), &registry.read(), format, pretty)
+}
+
+/// Collect every entity of `world` that carries a [`ComponentA`] into a scene.
+///
+/// Only the components that make up our scene are kept, so this can be called on the
+/// server world directly without dragging all the lightyear bookkeeping into the file.
+/// `world` needs an [`AppTypeRegistry`] resource, as the scene builder reads it from there.
+fn extract_scene(world: &World) -> DynamicScene {
+    let entities = world
+        .iter_entities()
+        .filter(|entity| entity.contains::<ComponentA>())
+        .map(|entity| entity.id());
+    extract_scene_entities(world, entities)
+}
+
+/// Same as [`extract_scene`], for the given entities only
+fn extract_scene_entities(world: &World, entities: impl Iterator<Item = Entity>) -> DynamicScene {
+    DynamicSceneBuilder::from_world(world)
+        .deny_all_resources()
+        .deny_all_components()
+        .allow_component::<NetId>()
+        .allow_component::<ComponentA>()
+        .allow_component::<ComponentB>()
+        .allow_component::<CarrierId>()
+        .allow_component::<Name>()
+        .extract_entities(entities)
+        .build()
+}
+
+/// Serialize the scene in the requested format
+fn serialize_scene(
+    scene: &DynamicScene,
+    type_registry: &TypeRegistry,
+    format: SceneFormat,
+    pretty: bool,
+) -> Result<Vec<u8>, SceneSerializationError> {
+    match format {
+        // `DynamicScene::serialize` always pretty prints
+        SceneFormat::Ron if pretty => scene
+            .serialize(type_registry)
+            .map(String::into_bytes)
+            .map_err(SceneSerializationError::Ron),
+        SceneFormat::Ron => {
+            bevy::scene::ron::to_string(&SceneSerializer::new(scene, type_registry))
+                .map(String::into_bytes)
+                .map_err(SceneSerializationError::Ron)
+        }
+        SceneFormat::Bincode => bincode::serialize(&SceneSerializer::new(scene, type_registry))
+            .map_err(SceneSerializationError::Bincode),
+    }
+}
+
+/// Version written at the start of every snapshot, bump it when the snapshot content
+/// changes in a way older snapshots can't be read with
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Serialize the whole replicated state of `world`: every entity carrying one of our
+/// replicated components, with their hierarchy. Meant for crash recovery with
+/// [`load_snapshot`].
+///
+/// The blob is the [`SNAPSHOT_VERSION`] as 4 little endian bytes followed by the scene in
+/// the [`SceneFormat::Bincode`] format. `world` needs an [`AppTypeRegistry`].
+pub fn save_snapshot(world: &World) -> Result<Vec<u8>, SceneSerializationError> {
+    let entities = world
+        .iter_entities()
+        .filter(|entity| {
+            entity.contains::<ComponentA>()
+                || entity.contains::<CarrierId>()
+                || entity.contains::<NetPosition>()
+        })
+        .map(|entity| entity.id());
+    let scene = DynamicSceneBuilder::from_world(world)
+        .deny_all_resources()
+        .deny_all_components()
+        .allow_component::<NetId>()
+        .allow_component::<ComponentA>()
+        .allow_component::<ComponentB>()
+        .allow_component::<CarrierId>()
+        .allow_component::<NetPosition>()
+        .allow_component::<Name>()
+        .allow_component::<Parent>()
+        .allow_component::<Children>()
+        .extract_entities(entities)
+        .build();
+    let registry = world.resource::<AppTypeRegistry>().read();
+    let mut data = SNAPSHOT_VERSION.to_le_bytes().to_vec();
+    data.extend(serialize_scene(
+        &scene,
+        &registry,
+        SceneFormat::Bincode,
+        false,
+    )?);
+    Ok(data)
+}
+
+/// Spawn the entities of a snapshot written by [`save_snapshot`] into `world`.
+///
+/// The entities come back without `Replicate`, so [`add_replicate`] starts replicating them
+/// again as their clients reconnect, based on their [`CarrierId`].
+pub fn load_snapshot(world: &mut World, bytes: &[u8]) -> Result<(), String> {
+    let (version, data) = bytes
+        .split_first_chunk::<4>()
+        .ok_or_else(|| "the snapshot is truncated".to_string())?;
+    let version = u32::from_le_bytes(*version);
+    if version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "snapshot version {} can't be read, expected {}",
+            version, SNAPSHOT_VERSION
+        ));
+    }
+    let app_type_registry = world.resource::<AppTypeRegistry>().clone();
+    let scene = {
+        let type_registry = app_type_registry.read();
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .deserialize_seed(
+                SceneDeserializer {
+                    type_registry: &type_registry,
+                },
+                data,
+            )
+            .map_err(|err| err.to_string())?
+    };
+    scene
+        .write_to_world(world, &mut default())
+        .map_err(|err| err.to_string())
+}
+
+/// Read a scene written with [`SceneFormat::Bincode`]
+fn load_binary_scene(
+    path: &Path,
+    app_type_registry: &AppTypeRegistry,
+) -> Result<DynamicScene, String> {
+    let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+    let type_registry = app_type_registry.read();
+    let scene_deserializer = SceneDeserializer {
+        type_registry: &type_registry,
+    };
+    // bincode::serialize uses fixed int encoding, so the deserializer has to match it
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .deserialize_seed(scene_deserializer, &bytes)
+        .map_err(|err| err.to_string())
+}
+
+/// Write the serialized scene, creating the parent directory if it doesn't exist yet.
+///
+/// The file is synced before returning, so once this succeeds the scene is durable.
+fn write_scene_file(path: &Path, data: &[u8]) -> std::io::Result<usize> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(data.len())
+}
+
+/// Move an existing scene file out of the way to `<stem>.<timestamp>-<n>.<ext>.bak`, then
+/// delete the oldest backups so that at most `max_backups` remain. `<n>` counts the backups
+/// made within the same second, so that they don't overwrite each other
+fn backup_scene_file(path: &Path, max_backups: usize) -> std::io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{stem}.");
+    let suffix = format!(".{extension}.bak");
+    let list_backups = || -> std::io::Result<Vec<((u64, u64), PathBuf)>> {
+        Ok(std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let key = name
+                    .strip_prefix(&prefix)?
+                    .strip_suffix(&suffix)
+                    .and_then(parse_backup_key)?;
+                Some((key, entry.path()))
+            })
+            .collect())
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let sequence = list_backups()?
+        .iter()
+        .filter(|((backup_timestamp, _), _)| *backup_timestamp == timestamp)
+        .map(|((_, sequence), _)| sequence + 1)
+        .max()
+        .unwrap_or(0);
+    let backup_path = path.with_file_name(format!("{stem}.{timestamp}-{sequence}.{extension}.bak"));
+    std::fs::rename(path, &backup_path)?;
+
+    let mut backups = list_backups()?;
+    backups.sort();
+    let excess = backups.len().saturating_sub(max_backups);
+    for (_, old_backup) in backups.drain(..excess) {
+        std::fs::remove_file(old_backup)?;
+    }
+    Ok(())
+}
+
+/// The timestamp and sequence number in the name of a backup, `<timestamp>-<n>`. Backups
+/// named before the sequence number was added, `<timestamp>` alone, count as number 0
+fn parse_backup_key(key: &str) -> Option<(u64, u64)> {
+    match key.split_once('-') {
+        Some((timestamp, sequence)) => Some((timestamp.parse().ok()?, sequence.parse().ok()?)),
+        None => Some((key.parse().ok()?, 0)),
+    }
+}
+
+/// Check on the running scene writes and report how each of them ended
+fn poll_scene_saves(
+    mut pending_saves: ResMut<PendingSceneSaves>,
+    mut saved_hashes: ResMut<SavedSceneHashes>,
+    mut saved_writer: EventWriter<SceneSaved>,
+    mut failed_writer: EventWriter<SceneSaveFailed>,
+) {
+    pending_saves.0.retain_mut(|save| {
+        let Some(result) = block_on(future::poll_once(&mut save.task)) else {
+            return true;
+        };
+        match result {
+            Ok(bytes) => {
+                info!("Saved scene to {:?} ({} bytes)", save.path, bytes);
+                saved_writer.send(SceneSaved {
+                    client_id: save.client_id,
+                    path: save.path.clone(),
+                    bytes,
+                });
+            }
+            Err(err) => {
+                error!(
+                    "Failed to save scene for client {:?}: {}",
+                    save.client_id, err
+                );
+                // what's on disk is unknown now, write it again next time
+                saved_hashes.0.remove(&save.path);
+                failed_writer.send(SceneSaveFailed {
+                    client_id: save.client_id,
+                    error: err.to_string(),
+                });
+            }
+        }
+        false
+    });
+}
+
+fn toggle_replication_pause(mut paused: ResMut<ReplicationPaused>) {
+    paused.0 = !paused.0;
+    if paused.0 {
+        info!("Replication paused");
+    } else {
+        info!("Replication resumed");
+    }
+}
+
+/// Keep the `Replicating` marker off every replicated entity while paused, including the
+/// ones `add_replicate` starts replicating in the meantime
+fn apply_replication_pause(
+    paused: Res<ReplicationPaused>,
+    replicating: Query<Entity, With<Replicating>>,
+    stopped: Query<Entity, (With<ReplicationTarget>, Without<Replicating>)>,
+    mut commands: Commands,
+) {
+    if paused.0 {
+        for entity in &replicating {
+            commands.entity(entity).remove::<Replicating>();
+        }
+    } else if paused.is_changed() {
+        for entity in &stopped {
+            commands.entity(entity).insert(Replicating);
+        }
+    }
+}
+
+/// A window listing the connected clients, with what they carry and a button to kick them
+#[cfg(all(feature = "inspector", not(feature = "headless")))]
+fn client_panel(
+    mut contexts: EguiContexts,
+    connected: Res<ConnectedClients>,
+    index: Res<CarrierIndex>,
+    lobby_config: Res<LobbyConfig>,
+    persisted: Res<PersistedRooms>,
+    players: Query<&ComponentA>,
+    mut kick_writer: EventWriter<KickClient>,
+) {
+    let mut clients: Vec<ClientId> = connected.iter().collect();
+    clients.sort_by_key(|client_id| client_id.to_bits());
+    egui::Window::new("Clients").show(contexts.ctx_mut(), |ui| {
+        egui::Grid::new("clients").striped(true).show(ui, |ui| {
+            ui.label("client");
+            ui.label("entity");
+            ui.label("room");
+            ui.label("component A");
+            ui.end_row();
+            for client_id in clients {
+                let entity = index.entity_for_client(client_id);
+                // the persisted rooms follow transfers, the lobby only knows the default room
+                let room = persisted
+                    .0
+                    .get(&client_id)
+                    .map_or(lobby_config.room_for(client_id), |member| {
+                        RoomId(member.room)
+                    });
+                let component_a = entity.and_then(|entity| players.get(entity).ok());
+                ui.label(format!("{:?}", client_id));
+                ui.label(entity.map_or("-".to_string(), |entity| entity.to_string()));
+                ui.label(format!("{:?}", room));
+                ui.label(component_a.map_or("-".to_string(), |a| a.0.to_string()));
+                if ui.button("kick").clicked() {
+                    kick_writer.send(KickClient {
+                        client_id,
+                        reason: "kicked from the client panel".to_string(),
+                    });
+                }
+                ui.end_row();
+            }
+        });
+    });
+}
+
+/// Kick the client that connected last when [`KICK_KEY`] is pressed
+fn kick_last_client(
+    keys: Option<Res<ButtonInput<KeyCode>>>,
+    connected: Res<ConnectedClients>,
+    mut accepted_events: EventReader<ClientAccepted>,
+    mut kick_writer: EventWriter<KickClient>,
+    mut last_client: Local<Option<ClientId>>,
+) {
+    if let Some(event) = accepted_events.read().last() {
+        *last_client = Some(event.client_id);
+    }
+    if !keys.is_some_and(|keys| keys.just_pressed(KICK_KEY)) {
+        return;
+    }
+    match *last_client {
+        Some(client_id) if connected.contains(client_id) => {
+            kick_writer.send(KickClient {
+                client_id,
+                reason: "kicked by the server".to_string(),
+            });
+        }
+        _ => info!("No client to kick"),
+    }
+}
+
+/// Hand every client the authority over the entity it carries when [`AUTHORITY_KEY`] is
+/// pressed
+fn give_authority_to_carriers(
+    keys: Option<Res<ButtonInput<KeyCode>>>,
+    players: Query<(Entity, &CarrierId), With<Replicated>>,
+    mut transfer_writer: EventWriter<TransferAuthority>,
+) {
+    if !keys.is_some_and(|keys| keys.just_pressed(AUTHORITY_KEY)) {
+        return;
+    }
+    for (entity, carrier_id) in players.iter() {
+        transfer_writer.send(TransferAuthority {
+            entity,
+            to: carrier_id.0,
+        });
+    }
+}
+
+/// Carry out the valid [`TransferAuthority`] requests
+fn transfer_authority(
+    connected: Res<ConnectedClients>,
+    players: Query<&CarrierId, With<Replicated>>,
+    mut transfer_events: EventReader<TransferAuthority>,
+    mut commands: Commands,
+) {
+    for event in transfer_events.read() {
+        let Ok(carrier_id) = players.get(event.entity) else {
+            warn!(
+                "Can't transfer the authority over {}, it isn't a replicated player",
+                event.entity
+            );
+            continue;
+        };
+        if carrier_id.0 != event.to || !connected.contains(event.to) {
+            warn!(
+                "Refusing to give {:?} the authority over {}, it doesn't carry it",
+                event.to, event.entity
+            );
+            continue;
+        }
+        info!("Giving {:?} the authority over {}", event.to, event.entity);
+        commands
+            .entity(event.entity)
+            .transfer_authority(AuthorityPeer::Client(event.to));
+    }
+}
+
+/// Disconnect the clients of every [`KickClient`]. Lightyear then sends the usual
+/// `ServerDisconnectEvent`, which despawns their entity and takes them out of their room
+fn kick_clients(
+    mut connections: ResMut<ServerConnections>,
+    mut kick_events: EventReader<KickClient>,
+) {
+    for event in kick_events.read() {
+        info!("Kicking {:?}: {}", event.client_id, event.reason);
+        if let Err(err) = connections.disconnect(event.client_id) {
+            error!("Failed to disconnect {:?}: {:?}", event.client_id, err);
+        }
+    }
+}
+
+/// Turn a Ctrl-C into a [`ShutdownRequest`]
+fn request_shutdown_on_signal(
+    signal: Res<ShutdownSignal>,
+    mut shutdown_writer: EventWriter<ShutdownRequest>,
+) {
+    if signal.0.swap(false, Ordering::SeqCst) {
+        info!("Received Ctrl-C");
+        shutdown_writer.send(ShutdownRequest);
+    }
+}
+
+fn request_shutdown(mut shutdown_writer: EventWriter<ShutdownRequest>) {
+    shutdown_writer.send(ShutdownRequest);
+}
+
+/// Append a row per client to [`STATS_CSV_PATH`] every second while [`RecordStats`] is set.
+/// The file is opened on the first row and flushed on shutdown
+#[cfg(not(target_arch = "wasm32"))]
+fn record_stats(
+    time: Res<Time>,
+    record: Res<RecordStats>,
+    tick: Res<CurrentTick>,
+    metrics: Res<NetMetrics>,
+    mut shutdown_events: EventReader<ShutdownRequest>,
+    mut writer: Local<Option<std::io::BufWriter<File>>>,
+    mut timer: Local<Option<Timer>>,
+) {
+    if !shutdown_events.is_empty() {
+        shutdown_events.clear();
+        if let Some(Err(err)) = writer.as_mut().map(|writer| writer.flush()) {
+            error!("Failed to flush {}: {}", STATS_CSV_PATH, err);
+        }
+    }
+    if !record.0 {
+        return;
+    }
+    let timer =
+        timer.get_or_insert_with(|| Timer::new(Duration::from_secs(1), TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    if writer.is_none() {
+        let is_new = !Path::new(STATS_CSV_PATH).exists();
+        let file = match File::options()
+            .create(true)
+            .append(true)
+            .open(STATS_CSV_PATH)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Failed to open {}: {}", STATS_CSV_PATH, err);
+                return;
+            }
+        };
+        let mut file = std::io::BufWriter::new(file);
+        if is_new {
+            let _ = writeln!(file, "tick,client_id,bytes_sent,bytes_recv,rtt_ms");
+        }
+        *writer = Some(file);
+    }
+    let Some(file) = writer.as_mut() else {
+        return;
+    };
+    let tick = tick.0 .0;
+    let mut result = writeln!(
+        file,
+        "{},all,{},{},",
+        tick, metrics.bytes_sent, metrics.bytes_received
+    );
+    for (client_id, client) in &metrics.clients {
+        result = result.and(writeln!(
+            file,
+            "{},{:?},{},{},{}",
+            tick,
+            client_id,
+            client.message_bytes_sent,
+            client.message_bytes_received,
+            client.rtt.as_millis()
+        ));
+    }
+    if let Err(err) = result {
+        error!("Failed to write to {}: {}", STATS_CSV_PATH, err);
+    }
+}
+
+/// Stop the server and exit the `App` once a [`ShutdownRequest`] is received. Scene saves
+/// still running are waited for so that no file is left half written
+fn stop_server(
+    mut shutdown_events: EventReader<ShutdownRequest>,
+    mut pending_saves: ResMut<PendingSceneSaves>,
+    replicated: Query<Entity, With<Replicated>>,
+    mut exit_writer: EventWriter<AppExit>,
+    mut commands: Commands,
+) {
+    if shutdown_events.is_empty() {
+        return;
+    }
+    shutdown_events.clear();
+    info!("Shutting down the server");
+
+    for save in pending_saves.0.drain(..) {
+        match block_on(save.task) {
+            Ok(bytes) => info!("Saved scene to {:?} ({} bytes)", save.path, bytes),
+            Err(err) => error!(
+                "Failed to save scene for client {:?}: {}",
+                save.client_id, err
+            ),
+        }
+    }
+    for entity in &replicated {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.stop_server();
+    exit_writer.send(AppExit::Success);
+}
+
+fn spawn_scene(
+    asset_server: Res<AssetServer>,
+    load_config: Res<SceneLoadConfig>,
+    app_type_registry: Res<AppTypeRegistry>,
+    mut scenes: ResMut<Assets<DynamicScene>>,
+    mut commands: Commands,
+) {
+    #[cfg(target_arch = "wasm32")]
+    if let Some((_, file)) = load_config
+        .url
+        .as_deref()
+        .and_then(|url| url.rsplit_once('/'))
+    {
+        let scene = asset_server.load(format!("{}://{}", SCENE_URL_SOURCE, file));
+        info!("Loading scene from {:?}", load_config.url);
+        commands
+            .spawn(DynamicSceneRoot(scene))
+            .insert(Name::new("MASTER PERI ENLIGHTEN US"));
+        return;
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if load_config.url.is_some() {
+        warn!(
+            "Scene URLs are only used on WASM, loading {:?}",
+            load_config.path
+        );
+    }
+    let scene = match SceneFormat::from_path(&load_config.path) {
+        SceneFormat::Ron => asset_server.load(load_config.path.clone()),
+        SceneFormat::Bincode => {
+            // Bevy's scene loader only understands RON, so binary scenes are read by hand
+            let path = Path::new("assets").join(&load_config.path);
+            match load_binary_scene(&path, &app_type_registry) {
+                Ok(scene) => scenes.add(scene),
+                Err(err) => {
+                    error!("Failed to load binary scene {:?}: {}", path, err);
+                    return;
+                }
+            }
+        }
+    };
+    info!("Loaded scene from assets");
+    commands
+        .spawn(DynamicSceneRoot(scene))
+        .insert(Name::new("MASTER PERI ENLIGHTEN US"));
+}
+
+/// Same as [`spawn_scene`], but spawns [`DEFAULT_SCENE_RON`] instead when the scene file
+/// doesn't exist.
+///
+/// On WASM the assets are served over HTTP so the file can't be checked up front, a failed
+/// load is cleaned up by [`despawn_failed_scene_roots`] instead.
+fn spawn_scene_if_exists(
+    asset_server: Res<AssetServer>,
+    load_config: Res<SceneLoadConfig>,
+    app_type_registry: Res<AppTypeRegistry>,
+    scenes: ResMut<Assets<DynamicScene>>,
+    commands: Commands,
+) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let path = Path::new("assets").join(&load_config.path);
+        if !path.exists() {
+            warn!("No scene found at {:?}, spawning the default scene", path);
+            spawn_default_scene(&app_type_registry, scenes, commands);
+            return;
+        }
+    }
+    spawn_scene(
+        asset_server,
+        load_config,
+        app_type_registry,
+        scenes,
+        commands,
+    );
+}
+
+/// Spawn the scene again when its file is modified, replacing the previous root and every
+/// entity it spawned. The asset server watches the file with the `hot_reload` feature, this
+/// only reacts to the reload.
+///
+/// The players of the old scene are despawned for the clients too, the new ones are
+/// replicated as their clients connect. Changes made by our own saves are ignored, otherwise
+/// every connect would respawn the scene.
+#[cfg(feature = "hot_reload")]
+fn reload_modified_scene(
+    time: Res<Time>,
+    mut asset_events: EventReader<AssetEvent<DynamicScene>>,
+    mut saved_events: EventReader<SceneSaved>,
+    roots: Query<(Entity, &DynamicSceneRoot, &Name)>,
+    asset_server: Res<AssetServer>,
+    mut reloaded_writer: EventWriter<SceneReloaded>,
+    mut commands: Commands,
+    mut own_writes: Local<HashMap<PathBuf, Duration>>,
+) {
+    const OWN_WRITE_WINDOW: Duration = Duration::from_secs(2);
+    let now = time.elapsed();
+    for event in saved_events.read() {
+        own_writes.insert(event.path.clone(), now);
+    }
+    own_writes.retain(|_, written_at| now.saturating_sub(*written_at) < OWN_WRITE_WINDOW);
+    for event in asset_events.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+        for (entity, root, name) in roots.iter() {
+            if root.0.id() != *id || name.as_str() != "MASTER PERI ENLIGHTEN US" {
+                continue;
+            }
+            let path = asset_server.get_path(*id).map(AssetPath::into_owned);
+            let written_by_us = path.as_ref().is_some_and(|path| {
+                own_writes.contains_key(&Path::new("assets").join(path.path()))
+            });
+            if written_by_us {
+                continue;
+            }
+            info!("Scene {:?} changed, spawning it again", path);
+            commands.entity(entity).despawn_recursive();
+            commands
+                .spawn(DynamicSceneRoot(root.0.clone()))
+                .insert(Name::new("MASTER PERI ENLIGHTEN US"));
+            reloaded_writer.send(SceneReloaded { path });
+        }
+    }
+}
+
+/// Mark every entity of a scene instance as soon as it is spawned
+fn tag_scene_entities(
+    trigger: Trigger<SceneInstanceReady>,
+    children: Query<&Children>,
+    net_ids: Query<&NetId>,
+    mut allocator: ResMut<NetIdAllocator>,
+    mut commands: Commands,
+) {
+    // saved scenes carry the ids they were given the first time, the new ones come after
+    for net_id in net_ids.iter_many(children.iter_descendants(trigger.entity())) {
+        allocator.reserve(*net_id);
+    }
+    for entity in children.iter_descendants(trigger.entity()) {
+        commands.entity(entity).insert(FromScene);
+        if !net_ids.contains(entity) {
+            commands.entity(entity).insert(allocator.allocate());
+        }
+    }
+}
+
+fn unload_scene(
+    mut unload_events: EventReader<UnloadScene>,
+    roots: Query<Entity, With<DynamicSceneRoot>>,
+    from_scene: Query<Entity, With<FromScene>>,
+    parents: Query<&Parent>,
+    mut commands: Commands,
+) {
+    if unload_events.is_empty() {
+        return;
+    }
+    unload_events.clear();
+    for root in roots.iter() {
+        info!("Unloading scene root {}", root);
+        commands.entity(root).despawn_recursive();
+    }
+    // scene entities moved out of their root since they were spawned
+    for entity in from_scene.iter() {
+        let under_root = parents
+            .iter_ancestors(entity)
+            .any(|ancestor| roots.contains(ancestor));
+        if !under_root {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+fn spawn_default_scene(
+    app_type_registry: &AppTypeRegistry,
+    mut scenes: ResMut<Assets<DynamicScene>>,
+    mut commands: Commands,
+) {
+    match parse_ron_scene(DEFAULT_SCENE_RON, app_type_registry) {
+        Ok(scene) => {
+            commands
+                .spawn(DynamicSceneRoot(scenes.add(scene)))
+                .insert(Name::new("MASTER PERI ENLIGHTEN US"));
+        }
+        Err(err) => error!("Failed to parse the default scene: {}", err),
+    }
+}
+
+/// Don't leave a scene root around if its scene failed to load (e.g. a 404 on WASM)
+fn despawn_failed_scene_roots(
+    mut failed_events: EventReader<AssetLoadFailedEvent<DynamicScene>>,
+    roots: Query<(Entity, &DynamicSceneRoot)>,
+    mut load_failed_writer: EventWriter<SceneLoadFailed>,
+    mut commands: Commands,
+) {
+    for event in failed_events.read() {
+        error!("Failed to load scene {}: {}", event.path, event.error);
+        load_failed_writer.send(SceneLoadFailed {
+            path: event.path.to_string(),
+            error: event.error.to_string(),
+        });
+        for (entity, root) in roots.iter() {
+            if root.0.id() == event.id {
+                warn!(
+                    "Despawning scene root {} as {} failed to load",
+                    entity, event.path
+                );
+                commands.entity(entity).despawn_recursive();
+            }
+        }
+    }
+}
+
+/// Look for component types in loaded scenes that the type registry doesn't know about
+fn validate_loaded_scenes(
+    mut asset_events: EventReader<AssetEvent<DynamicScene>>,
+    mut failed_events: EventReader<AssetLoadFailedEvent<DynamicScene>>,
+    scenes: Res<Assets<DynamicScene>>,
+    asset_server: Res<AssetServer>,
+    app_type_registry: Res<AppTypeRegistry>,
+    mut report_writer: EventWriter<SceneValidationReport>,
+) {
+    let type_registry = app_type_registry.read();
+    for event in asset_events.read() {
+        let AssetEvent::LoadedWithDependencies { id } = event else {
+            continue;
+        };
+        let Some(scene) = scenes.get(*id) else {
+            continue;
+        };
+        let mut unknown_types: Vec<String> = scene
+            .entities
+            .iter()
+            .flat_map(|entity| entity.components.iter())
+            .map(|component| {
+                component
+                    .get_represented_type_info()
+                    .map_or(component.reflect_type_path(), |info| info.type_path())
+            })
+            .filter(|type_path| {
+                type_registry
+                    .get_with_type_path(type_path)
+                    .and_then(|registration| registration.data::<ReflectComponent>())
+                    .is_none()
+            })
+            .map(str::to_string)
+            .collect();
+        unknown_types.sort();
+        unknown_types.dedup();
+        let path = asset_server.get_path(*id).map(AssetPath::into_owned);
+        for type_path in &unknown_types {
+            warn!("Scene {:?} uses unregistered component {}", path, type_path);
+        }
+        report_writer.send(SceneValidationReport {
+            path,
+            unknown_types,
+        });
+    }
+    // A type missing from the registry makes the whole load fail. The loader error only
+    // describes the first one, so read the scene again as plain data to find all of them.
+    // The error itself is logged by despawn_failed_scene_roots
+    for event in failed_events.read() {
+        let unknown_types = match &event.error {
+            AssetLoadError::AssetLoaderError(_) => read_scene_file(&event.path)
+                .map(|ron| unregistered_types_in_ron(&ron, &type_registry))
+                .unwrap_or_default(),
+            // the scene wasn't read at all
+            _ => Vec::new(),
+        };
+        for type_path in &unknown_types {
+            warn!(
+                "Scene {} uses unregistered component {}",
+                event.path, type_path
+            );
+        }
+        report_writer.send(SceneValidationReport {
+            path: Some(event.path.clone()),
+            unknown_types,
+        });
+    }
+}
+
+/// The text of a scene asset, read from the `assets` directory. `None` in the browser, where
+/// assets aren't files
+fn read_scene_file(path: &AssetPath) -> Option<String> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::fs::read_to_string(Path::new("assets").join(path.path())).ok()
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        None
+    }
 }
 
-impl Plugin for ExampleServerPlugin {
-    fn build(&self, app: &mut App) {
-        app.add_plugins(DefaultPlugins);
+/// A RON scene with only the type paths of the components, the values are skipped
+#[derive(Deserialize)]
+struct RawScene {
+    #[serde(default)]
+    entities: BTreeMap<u64, RawEntity>,
+}
 
-        // add lightyear plugins
-        app.add_plugins(build_server_plugin());
-        app.add_plugins(WorldInspectorPlugin::new());
+#[derive(Deserialize)]
+struct RawEntity {
+    #[serde(default)]
+    components: BTreeMap<String, IgnoredAny>,
+}
 
-        // add our shared plugin containing the protocol + other shared behaviour
-        app.add_plugins(SharedPlugin);
+/// The component types of a RON scene that the registry can't insert, in order and without
+/// duplicates. Empty if the text isn't a scene at all
+fn unregistered_types_in_ron(ron: &str, type_registry: &TypeRegistry) -> Vec<String> {
+    let Ok(scene) = bevy::scene::ron::from_str::<RawScene>(ron) else {
+        return Vec::new();
+    };
+    let mut unknown_types: Vec<String> = scene
+        .entities
+        .into_values()
+        .flat_map(|entity| entity.components.into_keys())
+        .filter(|type_path| {
+            type_registry
+                .get_with_type_path(type_path)
+                .and_then(|registration| registration.data::<ReflectComponent>())
+                .is_none()
+        })
+        .collect();
+    unknown_types.sort();
+    unknown_types.dedup();
+    unknown_types
+}
 
-        // add our server-specific logic. Here we will just start listening for incoming connections
-        app.add_systems(Startup, start_server);
+fn add_replicate(
+    query: Query<
+        (Entity, &CarrierId, Option<&ReplicationPriority>),
+        (With<ComponentA>, Without<Replicated>),
+    >,
+    mut commands: Commands,
+    mut rooms: ResMut<RoomManager>,
+    lobby_config: Res<LobbyConfig>,
+    relevance_mode: Res<RelevanceMode>,
+    target_config: Res<ReplicationTargetConfig>,
+    room_intervals: Res<RoomIntervals>,
+    mut net_ids: ResMut<NetIdAllocator>,
+    mut event_reader: EventReader<ClientAccepted>,
+    mut room_full_writer: EventWriter<RoomFull>,
+) {
+    // Commands are only applied at the end of the system, so handle every connect of this
+    // frame in a single pass to not replicate the same entity twice
+    let accepted: HashSet<ClientId> = event_reader.read().map(|e| e.client_id).collect();
+    if accepted.is_empty() {
+        return;
+    }
+    // one RoomFull per connect, however many entities the client carries
+    let mut turned_away = HashSet::new();
+    for (entity, carrier_id, priority) in query.iter() {
+        let client_id = carrier_id.0;
+        // an entity waits for its client to be accepted, so rejected clients never get one
+        if !accepted.contains(&client_id) {
+            continue;
+        }
+        let priority = priority.map_or(PLAYER_PRIORITY, |priority| priority.0);
+        // keyed by the player entity: the player and its parts are sent and applied together
+        let group = ReplicationGroup::new_id(entity.to_bits()).set_priority(priority);
 
-        app.add_systems(Startup, spawn_camera);
+        if let RelevanceMode::Distance { .. } = *relevance_mode {
+            // Interest management: update_distance_relevance picks the clients close enough
+            let replicate = Replicate {
+                target: ReplicationTarget {
+                    target: target_config.target_for(client_id),
+                },
+                sync: SyncTarget {
+                    prediction: NetworkTarget::Single(client_id),
+                    interpolation: NetworkTarget::AllExceptSingle(client_id),
+                },
+                relevance_mode: NetworkRelevanceMode::InterestManagement,
+                group,
+                ..default()
+            };
+            info!(
+                "Started to replicate entity {} with component A by distance",
+                entity
+            );
+            spawn_player_part(&mut commands, entity, &replicate, net_ids.allocate());
+            commands
+                .entity(entity)
+                .insert((replicate, Replicated))
+                .insert_if_new((
+                    NetPosition(spawn_position(client_id)),
+                    ComponentB(0.0),
+                    net_ids.allocate(),
+                ));
+        } else if lobby_config.use_rooms {
+            // Interest management: the entity is only sent to the clients sharing its room
+            let room_id = lobby_config.room_for(client_id);
+            let already_in_room = rooms
+                .get_room(room_id)
+                .is_some_and(|room| room.clients.contains(&client_id));
+            let room_size = room_client_count(&rooms, room_id);
+            if !already_in_room && room_size >= lobby_config.room_capacity() {
+                warn!(
+                    "Room {:?} is full ({} clients), not replicating entity {} of {:?}",
+                    room_id, room_size, entity, client_id
+                );
+                if turned_away.insert(client_id) {
+                    room_full_writer.send(RoomFull { client_id, room_id });
+                }
+                continue;
+            }
+            let replicate = Replicate {
+                target: ReplicationTarget {
+                    target: target_config.target_for(client_id),
+                },
+                // the carrier predicts its own entity, the other clients interpolate it
+                sync: SyncTarget {
+                    prediction: NetworkTarget::Single(client_id),
+                    interpolation: NetworkTarget::AllExceptSingle(client_id),
+                },
+                relevance_mode: NetworkRelevanceMode::InterestManagement,
+                group: group.set_send_frequency(room_intervals.interval_for(room_id)),
+                ..default()
+            };
+            let part = spawn_player_part(&mut commands, entity, &replicate, net_ids.allocate());
+            rooms.add_client(client_id, room_id);
+            rooms.add_entity(entity, room_id);
+            rooms.add_entity(part, room_id);
+            info!(
+                "Started to replicate entity {} with component A in lobby",
+                entity
+            );
+            commands
+                .entity(entity)
+                .insert((replicate, Replicated))
+                .insert_if_new((
+                    NetPosition(spawn_position(client_id)),
+                    ComponentB(0.0),
+                    net_ids.allocate(),
+                ));
+        } else {
+            // No interest management: the entity is sent to every connected client
+            let replicate = Replicate {
+                target: ReplicationTarget {
+                    target: target_config.target_for(client_id),
+                },
+                sync: SyncTarget {
+                    prediction: NetworkTarget::Single(client_id),
+                    interpolation: NetworkTarget::AllExceptSingle(client_id),
+                },
+                group,
+                ..default()
+            };
+            info!("Started to replicate entity {} with component A", entity);
+            spawn_player_part(&mut commands, entity, &replicate, net_ids.allocate());
+            commands
+                .entity(entity)
+                .insert((replicate, Replicated))
+                .insert_if_new((
+                    NetPosition(spawn_position(client_id)),
+                    ComponentB(0.0),
+                    net_ids.allocate(),
+                ));
+        };
+    }
+}
 
-        // Run this if you want to make a new scene
-        app.add_systems(Update, create_save_scene);
+/// Spawn the `ComponentA(0)` part of a player as a child of its entity.
+///
+/// The part is replicated with the same settings as its parent, `replicate` must carry the
+/// parent's group so that both always reach the client together. It has no [`CarrierId`], so
+/// it doesn't count as a player of its own.
+///
+/// `Parent` and `Children` can't be replicated as they are, they hold server entities.
+/// Lightyear mirrors the `Parent` into [`ParentSync`] (which it registers itself), maps the
+/// entity on the client and rebuilds the `Parent` there. Being in the same group guarantees
+/// the parent already exists on the client when the child arrives.
+fn spawn_player_part(
+    commands: &mut Commands,
+    parent: Entity,
+    replicate: &Replicate,
+    net_id: NetId,
+) -> Entity {
+    let part = commands
+        .spawn((
+            ComponentA(0),
+            net_id,
+            replicate.clone(),
+            ParentSync::default(),
+        ))
+        .id();
+    commands.entity(parent).add_child(part);
+    part
+}
 
-        // Run this to load scene
-        app.add_systems(Startup, spawn_scene);
+/// With [`RelevanceMode::Distance`], make every replicated entity (and its children) relevant
+/// to the clients whose own entity is within the radius, and irrelevant to the others.
+///
+/// Only the changes are sent to the [`RelevanceManager`], the pairs currently relevant are
+/// remembered between runs.
+fn update_distance_relevance(
+    relevance_mode: Res<RelevanceMode>,
+    index: Res<CarrierIndex>,
+    connected: Res<ConnectedClients>,
+    entities: Query<(Entity, &NetPosition), With<Replicated>>,
+    children: Query<&Children>,
+    mut relevance: ResMut<RelevanceManager>,
+    mut relevant: Local<HashSet<(ClientId, Entity)>>,
+) {
+    let RelevanceMode::Distance { radius } = *relevance_mode else {
+        return;
+    };
+    let radius_squared = radius * radius;
+    let mut now_relevant = HashSet::default();
+    for client_id in connected.iter() {
+        let Some(own_position) = index
+            .entity_for_client(client_id)
+            .and_then(|own| entities.get(own).ok())
+            .map(|(_, position)| position.0)
+        else {
+            continue;
+        };
+        for (entity, position) in entities.iter() {
+            if position.0.distance_squared(own_position) <= radius_squared {
+                now_relevant.insert((client_id, entity));
+                // the player parts follow their parent
+                for child in children.iter_descendants(entity) {
+                    now_relevant.insert((client_id, child));
+                }
+            }
+        }
+    }
+    for &(client_id, entity) in now_relevant.difference(&relevant) {
+        relevance.gain_relevance(client_id, entity);
+    }
+    for &(client_id, entity) in relevant.difference(&now_relevant) {
+        relevance.lose_relevance(client_id, entity);
+    }
+    *relevant = now_relevant;
+}
 
-        // Replicate
-        app.add_systems(Update, add_replicate);
+/// Keep the [`CarrierIndex`] in sync with the `CarrierId` components.
+///
+/// An entity losing its `CarrierId` (e.g. given back to a pool) also leaves the room of its
+/// former carrier, along with its children, so that it stops being replicated to that room.
+/// The room is looked up before the entity is dropped from the index.
+fn update_carrier_index(
+    mut index: ResMut<CarrierIndex>,
+    mut rooms: ResMut<RoomManager>,
+    lobby_config: Res<LobbyConfig>,
+    persisted: Res<PersistedRooms>,
+    children: Query<&Children>,
+    added: Query<(Entity, &CarrierId), Added<CarrierId>>,
+    added_net_ids: Query<(Entity, &NetId), Added<NetId>>,
+    mut removed: RemovedComponents<CarrierId>,
+    mut removed_net_ids: RemovedComponents<NetId>,
+    mut allocator: ResMut<NetIdAllocator>,
+) {
+    for entity in removed_net_ids.read() {
+        index.by_net_id.retain(|_, indexed| *indexed != entity);
+    }
+    for (entity, net_id) in added_net_ids.iter() {
+        index.by_net_id.insert(*net_id, entity);
+        // the ids loaded with a snapshot are never handed out again
+        allocator.reserve(*net_id);
+    }
+    for entity in removed.read() {
+        let carriers: Vec<ClientId> = index
+            .by_client
+            .iter()
+            .filter(|(_, indexed)| indexed.contains(&entity))
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in carriers {
+            if let Some(entities) = index.by_client.get_mut(&client_id) {
+                entities.retain(|indexed| *indexed != entity);
+                if entities.is_empty() {
+                    index.by_client.remove(&client_id);
+                }
+            }
+            if !lobby_config.use_rooms {
+                continue;
+            }
+            // the persisted room follows the transfers, unlike `room_for`
+            let room_id = persisted.0.get(&client_id).map_or_else(
+                || lobby_config.room_for(client_id),
+                |member| RoomId(member.room),
+            );
+            for member in std::iter::once(entity).chain(children.iter_descendants(entity)) {
+                rooms.remove_entity(member, room_id);
+            }
+            debug!(
+                "Entity {} is no longer carried by {:?}, removed it from room {:?}",
+                entity, client_id, room_id
+            );
+        }
+    }
+    for (entity, carrier_id) in added.iter() {
+        let entities = index.by_client.entry(carrier_id.0).or_default();
+        if !entities.contains(&entity) {
+            entities.push(entity);
+        }
     }
 }
 
-/// Start the server
-fn start_server(mut commands: Commands) {
-    commands.start_server();
+/// Despawn the entities carried by clients that disconnected, so they stop being replicated
+///
+/// The entity is only marked as [`AwaitingReconnect`] at first, and despawned by
+/// [`despawn_expired_players`] if the client doesn't come back in time
+fn despawn_disconnected_players(
+    time: Res<Time>,
+    index: Res<CarrierIndex>,
+    mut commands: Commands,
+    mut event_reader: EventReader<ServerDisconnectEvent>,
+) {
+    for event in event_reader.read() {
+        for &entity in index.entities_for_client(event.client_id) {
+            commands.entity(entity).insert(AwaitingReconnect {
+                since: time.elapsed(),
+            });
+        }
+    }
 }
 
-fn spawn_camera(mut commands: Commands) {
-    commands.spawn(Camera3d::default());
+/// Despawn the entities whose client didn't reconnect within the grace period, and forget
+/// the ones despawned longer than [`ConnectionLimits::forget_after`] ago
+fn despawn_expired_players(
+    time: Res<Time>,
+    limits: Res<ConnectionLimits>,
+    players: Query<(Entity, &CarrierId, &ComponentA, &AwaitingReconnect)>,
+    mut despawned: ResMut<DespawnedPlayers>,
+    mut commands: Commands,
+) {
+    let now = time.elapsed();
+    despawned
+        .0
+        .retain(|_, (_, despawned_at)| now.saturating_sub(*despawned_at) < limits.forget_after);
+    for (entity, carrier_id, component_a, awaiting) in players.iter() {
+        if now.saturating_sub(awaiting.since) < limits.reconnect_grace {
+            continue;
+        }
+        info!(
+            "Despawning entity {} of disconnected client {:?}",
+            entity, carrier_id.0
+        );
+        despawned.0.insert(carrier_id.0, (component_a.0, now));
+        // Recursive so the child ComponentA goes away too
+        commands.entity(entity).despawn_recursive();
+    }
 }
 
-// Here we create a very simple dynamic scene asset
-fn create_save_scene(
-    app_type_registry: Res<AppTypeRegistry>,
-    mut event_reader: EventReader<ServerConnectEvent>,
+/// Rank the entity each client carries above the others for that client, see
+/// [`OWN_ENTITY_PRIORITY_BOOST`].
+///
+/// Lightyear resets the priority of a group to the one of its `ReplicationGroup` whenever it
+/// spawns the group on a client, so this runs every frame, between the spawns and the
+/// sending of the updates
+fn prioritize_own_entities(
+    connected: Res<ConnectedClients>,
+    mut connection: ResMut<ConnectionManager>,
+    players: Query<(Entity, &CarrierId, Option<&ReplicationPriority>), With<Replicated>>,
+) {
+    for (entity, carrier_id, priority) in players.iter() {
+        let client_id = carrier_id.0;
+        if !connected.contains(client_id) {
+            continue;
+        }
+        let priority = priority.map_or(PLAYER_PRIORITY, |priority| priority.0);
+        // the group is keyed by the player entity, as in add_replicate
+        let group_id = ReplicationGroupId(entity.to_bits());
+        if let Err(err) =
+            connection.update_priority(group_id, client_id, priority * OWN_ENTITY_PRIORITY_BOOST)
+        {
+            debug!(
+                "Failed to prioritize {} for {:?}: {:?}",
+                entity, client_id, err
+            );
+        }
+    }
+}
+
+/// Give reconnecting clients their entities back: the ones kept since they left, or a new one
+/// holding the `ComponentA` they had when it was despawned
+fn reattach_reconnected_players(
+    index: Res<CarrierIndex>,
+    lobby_config: Res<LobbyConfig>,
+    persisted: Res<PersistedRooms>,
+    relevance_mode: Res<RelevanceMode>,
+    awaiting: Query<(), With<AwaitingReconnect>>,
+    children: Query<&Children>,
+    mut rooms: ResMut<RoomManager>,
+    mut despawned: ResMut<DespawnedPlayers>,
+    mut accepted_events: EventReader<ClientAccepted>,
+    mut commands: Commands,
+) {
+    for event in accepted_events.read() {
+        let client_id = event.client_id;
+        let entities = index.entities_for_client(client_id);
+        // forgotten whether or not the client still has an entity
+        let despawned_value = despawned.0.remove(&client_id);
+        if entities.is_empty() {
+            let Some((value, _)) = despawned_value else {
+                continue;
+            };
+            info!(
+                "{:?} reconnected, recreating its entity with ComponentA({})",
+                client_id, value
+            );
+            // add_replicate picks it up right after
+            commands.spawn((
+                ComponentA(value),
+                CarrierId(client_id),
+                Name::new("Replicated entity"),
+            ));
+            continue;
+        }
+        for &entity in entities.iter().filter(|&&entity| awaiting.contains(entity)) {
+            info!("{:?} reconnected, reusing entity {}", client_id, entity);
+            commands.entity(entity).remove::<AwaitingReconnect>();
+            // cleanup_rooms_on_disconnect took it out of its room
+            if lobby_config.use_rooms && *relevance_mode == RelevanceMode::Rooms {
+                let room_id = persisted.room_of(client_id, &lobby_config);
+                rooms.add_client(client_id, room_id);
+                rooms.add_entity(entity, room_id);
+                for child in children.iter_descendants(entity) {
+                    rooms.add_entity(child, room_id);
+                }
+            }
+        }
+    }
+}
+
+/// Take disconnected clients, and the entities they carried, out of their room
+fn cleanup_rooms_on_disconnect(
+    index: Res<CarrierIndex>,
+    children: Query<&Children>,
+    mut rooms: ResMut<RoomManager>,
+    lobby_config: Res<LobbyConfig>,
+    persisted: Res<PersistedRooms>,
+    mut event_reader: EventReader<ServerDisconnectEvent>,
 ) {
     for event in event_reader.read() {
         let client_id = event.client_id;
-        // Grab registry just for serializaitopn
-        let mut scene_world = World::new();
-        let type_registry = app_type_registry.clone();
-        scene_world.insert_resource(type_registry);
+        let room_id = persisted.room_of(client_id, &lobby_config);
+        rooms.remove_client(client_id, room_id);
+        for &entity in index.entities_for_client(client_id) {
+            rooms.remove_entity(entity, room_id);
+            for child in children.iter_descendants(entity) {
+                rooms.remove_entity(child, room_id);
+            }
+        }
+        let is_empty = rooms
+            .get_room(room_id)
+            .is_none_or(|room| room.clients.is_empty() && room.entities.is_empty());
+        if is_empty {
+            info!("Room {:?} is empty after {:?} left", room_id, client_id);
+        }
+    }
+}
 
-        // Component A being add
-        scene_world
-            .spawn(ComponentA(2))
-            .insert(CarrierId(client_id))
-            .insert(Name::new("Replicated entity"));
+/// Read back the rooms saved by [`persist_rooms`], dropping the clients gone for too long
+fn load_persisted_rooms(persistence: Res<RoomPersistence>, mut persisted: ResMut<PersistedRooms>) {
+    let Ok(text) = std::fs::read_to_string(&persistence.path) else {
+        return;
+    };
+    let members: Vec<RoomMember> = match bevy::scene::ron::from_str(&text) {
+        Ok(members) => members,
+        Err(err) => {
+            warn!(
+                "Ignoring invalid rooms file {:?}: {}",
+                persistence.path, err
+            );
+            return;
+        }
+    };
+    let now = unix_secs();
+    persisted.0 = members
+        .into_iter()
+        .filter(|member| now.saturating_sub(member.last_seen) <= persistence.ttl.as_secs())
+        .map(|member| (member.client_id, member))
+        .collect();
+    info!(
+        "Restored the rooms of {} client(s) from {:?}",
+        persisted.0.len(),
+        persistence.path
+    );
+}
 
-        info!("Resulting scene world {:?}", scene_world);
-        let scene = DynamicScene::from_world(&scene_world);
+/// Send the clients that were in another room before back there once `add_replicate` put
+/// them in their default room. The clients [`reattach_reconnected_players`] already put
+/// back in their room stay there
+fn restore_rooms_on_connect(
+    rooms: Res<RoomManager>,
+    lobby_config: Res<LobbyConfig>,
+    mut persisted: ResMut<PersistedRooms>,
+    mut accepted_events: EventReader<ClientAccepted>,
+    mut transfer_writer: EventWriter<TransferClientRequest>,
+) {
+    for event in accepted_events.read() {
+        let client_id = event.client_id;
+        let default_room = lobby_config.room_for(client_id);
+        let member = persisted.0.entry(client_id).or_insert(RoomMember {
+            client_id,
+            room: default_room.0,
+            last_seen: 0,
+        });
+        member.last_seen = unix_secs();
+        let room_id = RoomId(member.room);
+        let back_in_room = rooms
+            .get_room(room_id)
+            .is_some_and(|room| room.clients.contains(&client_id));
+        if lobby_config.use_rooms && room_id != default_room && !back_in_room {
+            info!("Sending {:?} back to room {}", client_id, member.room);
+            transfer_writer.send(TransferClientRequest {
+                client_id,
+                from: default_room,
+                to: room_id,
+            });
+        }
+    }
+}
 
-        // Scenes can be serialized like this:
-        let type_registry = app_type_registry.clone();
-        let type_registry = type_registry.read();
-        let serialized_scene = scene.serialize(&type_registry).unwrap();
+/// Follow the clients moving between rooms and leaving. Only the transfers carried out
+/// count, so the saved rooms match the [`RoomManager`]
+fn track_rooms(
+    mut persisted: ResMut<PersistedRooms>,
+    mut transferred_events: EventReader<ClientTransferred>,
+    mut disconnect_events: EventReader<ServerDisconnectEvent>,
+) {
+    for event in transferred_events.read() {
+        if let Some(member) = persisted.0.get_mut(&event.client_id) {
+            member.room = event.to.0;
+        }
+    }
+    for event in disconnect_events.read() {
+        if let Some(member) = persisted.0.get_mut(&event.client_id) {
+            member.last_seen = unix_secs();
+        }
+    }
+}
 
-        // Showing the scene in the console
-        #[cfg(not(target_arch = "wasm32"))]
-        IoTaskPool::get()
-            .spawn(async move {
-                // Write the scene RON data to file
-                File::create(format!("assets/scene.ron",))
-                    .and_then(|mut file| file.write(serialized_scene.as_bytes()))
-                    .expect("Error while writing scene to file");
-            })
-            .detach();
+/// Write the room of every client to [`RoomPersistence::path`], every `save_interval` and
+/// on shutdown
+fn persist_rooms(
+    time: Res<Time>,
+    persistence: Res<RoomPersistence>,
+    connected: Res<ConnectedClients>,
+    mut persisted: ResMut<PersistedRooms>,
+    mut shutdown_events: EventReader<ShutdownRequest>,
+    mut timer: Local<Option<Timer>>,
+) {
+    let timer =
+        timer.get_or_insert_with(|| Timer::new(persistence.save_interval, TimerMode::Repeating));
+    let shutting_down = !shutdown_events.is_empty();
+    shutdown_events.clear();
+    if !timer.tick(time.delta()).just_finished() && !shutting_down {
+        return;
+    }
+    let now = unix_secs();
+    persisted.0.retain(|client_id, member| {
+        if connected.contains(*client_id) {
+            member.last_seen = now;
+        }
+        now.saturating_sub(member.last_seen) <= persistence.ttl.as_secs()
+    });
+    let members: Vec<&RoomMember> = persisted.0.values().collect();
+    let result = bevy::scene::ron::ser::to_string_pretty(&members, default())
+        .map_err(|err| err.to_string())
+        .and_then(|text| std::fs::write(&persistence.path, text).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        error!(
+            "Failed to save the rooms to {:?}: {}",
+            persistence.path, err
+        );
     }
 }
 
-fn spawn_scene(asset_server: Res<AssetServer>, mut commands: Commands) {
-    info!("Loaded scene from assets");
-    commands
-        .spawn(DynamicSceneRoot(asset_server.load("scene.ron")))
-        .insert(Name::new("MASTER PERI ENLIGHTEN US"));
+/// Start a game with the clients waiting in the lobby once there are enough of them, see
+/// [`Matchmaker`]. The moves go through [`TransferClientRequest`]
+fn matchmake(
+    rooms: Res<RoomManager>,
+    lobby_config: Res<LobbyConfig>,
+    mut matchmaker: ResMut<Matchmaker>,
+    mut registry: ResMut<RoomRegistry>,
+    mut transfer_writer: EventWriter<TransferClientRequest>,
+    mut match_writer: EventWriter<MatchStarted>,
+) {
+    let Some(lobby) = lobby_config.shared_room else {
+        return;
+    };
+    let Some(room) = rooms.get_room(lobby) else {
+        return;
+    };
+    if room.clients.len() < matchmaker.min_players.max(1) {
+        return;
+    }
+    let mut players: Vec<ClientId> = room.clients.iter().copied().collect();
+    // the same clients get picked whatever the iteration order of the room
+    players.sort_by_key(|client_id| client_id.to_bits());
+    players.truncate(matchmaker.max_players.min(lobby_config.room_capacity()));
+
+    matchmaker.games += 1;
+    let room_id = registry.room(&format!("game-{}", matchmaker.games));
+    for client_id in &players {
+        transfer_writer.send(TransferClientRequest {
+            client_id: *client_id,
+            from: lobby,
+            to: room_id,
+        });
+    }
+    info!("Starting a game in room {:?} with {:?}", room_id, players);
+    match_writer.send(MatchStarted { room_id, players });
 }
 
-fn add_replicate(
-    query: Query<(Entity, &CarrierId), With<ComponentA>>,
-    mut commands: Commands,
+/// Carry out the room transfers requested through [`TransferClientRequest`]
+fn handle_transfer_requests(
+    index: Res<CarrierIndex>,
+    children: Query<&Children>,
     mut rooms: ResMut<RoomManager>,
-    mut lobby_yes_or_no: Local<bool>,
-    mut event_reader: EventReader<ServerConnectEvent>
-) {
-    for event in event_reader.read(){
-        for (entity, carrier_id) in query.iter() {
-            let client_id = carrier_id.0;
-            *lobby_yes_or_no = true;
-    
-             if *lobby_yes_or_no {
-                let room_id = RoomId(client_id.to_bits());
-                let replicate = Replicate {
-                    target: ReplicationTarget {
-                        target: NetworkTarget::All,
-                    },
-                    relevance_mode: NetworkRelevanceMode::InterestManagement,
-                    ..default()
-                };
-                rooms.add_client(client_id, room_id);
-                rooms.add_entity(entity, room_id);
-                info!(
-                    "Started to replicate entity {} with component A in lobby",
-                    entity
+    mut event_reader: EventReader<TransferClientRequest>,
+    mut transferred_writer: EventWriter<ClientTransferred>,
+) {
+    for request in event_reader.read() {
+        // the player parts move along with their parent
+        let entities: Vec<Entity> = index
+            .entities_for_client(request.client_id)
+            .iter()
+            .flat_map(|&entity| std::iter::once(entity).chain(children.iter_descendants(entity)))
+            .collect();
+        let moved = transfer_client(
+            &mut rooms,
+            request.client_id,
+            &entities,
+            request.from,
+            request.to,
+        );
+        if moved {
+            transferred_writer.send(ClientTransferred {
+                client_id: request.client_id,
+                from: request.from,
+                to: request.to,
+            });
+        }
+    }
+}
+
+/// Log every room the server assigned clients to, with the clients and entities inside.
+///
+/// Rooms are found from the carried entities and the [`LobbyConfig`], as the
+/// [`RoomManager`] can't be iterated.
+fn dump_rooms(
+    rooms: Res<RoomManager>,
+    lobby_config: Res<LobbyConfig>,
+    carriers: Query<&CarrierId>,
+) {
+    let mut room_ids: Vec<RoomId> = carriers
+        .iter()
+        .map(|carrier_id| lobby_config.room_for(carrier_id.0))
+        .chain(lobby_config.shared_room)
+        .collect();
+    room_ids.sort_by_key(|room_id| room_id.0);
+    room_ids.dedup();
+
+    let membership = rooms.membership(room_ids);
+    info!("{} room(s):", membership.len());
+    info!("{:<24} | {:<40} | entities", "room", "clients");
+    for (room_id, clients, entities) in membership {
+        info!(
+            "{:<24} | {:<40} | {:?}",
+            format!("{:?}", room_id),
+            format!("{:?}", clients),
+            entities
+        );
+    }
+}
+
+/// Send the [`GameScore`] to every client when it changes, and to the clients that just
+/// connected
+fn send_game_score(
+    score: Res<GameScore>,
+    mut connection: ResMut<ConnectionManager>,
+    mut accepted_events: EventReader<ClientAccepted>,
+) {
+    let target = if score.is_changed() {
+        NetworkTarget::All
+    } else {
+        let new_clients: Vec<ClientId> = accepted_events.read().map(|e| e.client_id).collect();
+        if new_clients.is_empty() {
+            return;
+        }
+        NetworkTarget::Only(new_clients)
+    };
+    accepted_events.clear();
+    if let Err(err) = connection.send_message_to_target::<Channel1, _>(&mut score.clone(), target) {
+        error!("Failed to send the game score: {:?}", err);
+    }
+}
+
+/// Echo every [`Ping`] back to its sender, on the unreliable channel it came on
+fn answer_pings(
+    mut connection: ResMut<ConnectionManager>,
+    mut metrics: ResMut<NetMetrics>,
+    mut ping_events: EventReader<ServerMessageEvent<Ping>>,
+) {
+    for event in ping_events.read() {
+        let mut pong = Pong {
+            sent_tick: event.message().sent_tick,
+        };
+        if let Err(err) = connection.send_message::<Channel2, _>(*event.context(), &mut pong) {
+            error!(
+                "Failed to answer the ping of {:?}: {:?}",
+                *event.context(),
+                err
+            );
+            continue;
+        }
+        let client = metrics.clients.entry(*event.context()).or_default();
+        client.rtt = Duration::from_millis(event.message().rtt_ms.into());
+        client.message_bytes_sent += bincode::serialized_size(&pong).unwrap_or_default();
+    }
+}
+
+/// Id of the next payload split into [`Fragment`]s, so that two payloads in flight to the
+/// same client never share one
+#[derive(Resource, Clone, Copy, Debug, Default)]
+struct NextPayloadId(u32);
+
+/// How often a client may ask for the scene with a [`RequestSceneSnapshot`], the requests
+/// in between are ignored
+pub const SCENE_REQUEST_COOLDOWN: Duration = Duration::from_secs(5);
+
+/// Send `client_id` the scene entities of the server world as a [`SceneSnapshot`] built by
+/// [`serialize_current_scene`]. Snapshots larger than [`FragmentConfig::fragment_size`] go as
+/// [`Fragment`]s of the bincode encoded `SceneSnapshot`
+fn send_scene_snapshot(world: &mut World, client_id: ClientId) {
+    let mut snapshot = match serialize_current_scene(world) {
+        Ok(ron) => SceneSnapshot { ron },
+        Err(err) => {
+            error!("Failed to serialize the scene for {:?}: {}", client_id, err);
+            return;
+        }
+    };
+    let fragment_size = world.resource::<FragmentConfig>().fragment_size;
+    let result = if snapshot.ron.len() <= fragment_size {
+        world
+            .resource_mut::<ConnectionManager>()
+            .send_message::<Channel1, _>(client_id, &mut snapshot)
+    } else {
+        let bytes = bincode::serialize(&snapshot).unwrap_or_default();
+        let mut next_payload_id = world.resource_mut::<NextPayloadId>();
+        next_payload_id.0 = next_payload_id.0.wrapping_add(1);
+        let payload_id = next_payload_id.0;
+        let mut connection = world.resource_mut::<ConnectionManager>();
+        let mut result = Ok(());
+        for mut fragment in split_into_fragments(payload_id, &bytes, fragment_size) {
+            result = connection.send_message::<Channel1, _>(client_id, &mut fragment);
+            if result.is_err() {
+                break;
+            }
+        }
+        result
+    };
+    match result {
+        Ok(()) => {
+            info!(
+                "Sent the scene to {:?} ({} bytes)",
+                client_id,
+                snapshot.ron.len()
+            );
+            let baseline = world.resource::<SceneEntityHashes>().baseline();
+            world
+                .resource_mut::<SentScenes>()
+                .0
+                .insert(client_id, baseline);
+        }
+        Err(err) => error!("Failed to send the scene to {:?}: {:?}", client_id, err),
+    }
+}
+
+/// How often [`send_scene_deltas`] looks for changes in the scene
+pub const SCENE_DELTA_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A scene entity as [`send_scene_deltas`] last serialized it on its own
+#[derive(Clone, Copy, Debug)]
+struct SceneEntityHash {
+    entity: Entity,
+    hash: u64,
+    len: usize,
+}
+
+/// Every scene entity by [`NetId`], only serialized again when one of its saved components
+/// changes
+#[derive(Resource, Default)]
+struct SceneEntityHashes(HashMap<NetId, SceneEntityHash>);
+
+impl SceneEntityHashes {
+    /// The hashes a client holds once it was sent the whole scene
+    fn baseline(&self) -> HashMap<NetId, u64> {
+        self.0
+            .iter()
+            .map(|(net_id, entity)| (*net_id, entity.hash))
+            .collect()
+    }
+}
+
+/// The scene each client holds, as the hash of every entity it was last sent in a
+/// [`SceneSnapshot`] or a [`SceneDelta`]
+#[derive(Resource, Default)]
+struct SentScenes(HashMap<ClientId, HashMap<NetId, u64>>);
+
+/// Send every client the changes of the scene entities (the ones tagged [`FromScene`]) since
+/// what it was last sent, as a [`SceneDelta`]. Entities are told apart by their [`NetId`],
+/// the ones without are left out.
+///
+/// The changes are taken against the scene the client holds, see [`SentScenes`], which the
+/// snapshot sent on connect starts. A client without one, or whose delta would be larger
+/// than the whole scene, gets the whole scene with [`send_scene_snapshot`] instead.
+fn send_scene_deltas(
+    world: &mut World,
+    changed_entities: &mut SystemState<
+        Query<
+            (Entity, &NetId),
+            (
+                With<FromScene>,
+                Or<(
+                    Added<FromScene>,
+                    Changed<NetId>,
+                    Changed<ComponentA>,
+                    Changed<ComponentB>,
+                    Changed<CarrierId>,
+                    Changed<Name>,
+                )>,
+            ),
+        >,
+    >,
+    mut timer: Local<Option<Timer>>,
+) {
+    let delta_time = world.resource::<Time>().delta();
+    let timer = timer.get_or_insert_with(|| Timer::new(SCENE_DELTA_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(delta_time).just_finished() {
+        return;
+    }
+
+    // only the entities that changed since the last time are serialized again
+    let changed: Vec<(Entity, NetId)> = changed_entities
+        .get(world)
+        .iter()
+        .map(|(entity, net_id)| (entity, *net_id))
+        .collect();
+    let app_type_registry = world.resource::<AppTypeRegistry>().clone();
+    let type_registry = app_type_registry.read();
+    let serialize = |world: &World, entities: &[Entity]| {
+        extract_scene_entities(world, entities.iter().copied())
+            .serialize(&type_registry)
+            .unwrap_or_default()
+    };
+    world.resource_scope(|world, mut hashes: Mut<SceneEntityHashes>| {
+        hashes.0.retain(|net_id, scene_entity| {
+            world.get::<NetId>(scene_entity.entity) == Some(net_id)
+                && world.get::<FromScene>(scene_entity.entity).is_some()
+        });
+        for (entity, net_id) in changed {
+            let ron = serialize(world, &[entity]);
+            let scene_entity = SceneEntityHash {
+                entity,
+                hash: scene_hash(ron.as_bytes()),
+                len: ron.len(),
+            };
+            hashes.0.insert(net_id, scene_entity);
+        }
+    });
+
+    let clients: Vec<ClientId> = world
+        .resource::<ConnectedClients>()
+        .iter()
+        .filter(|client_id| !matches!(client_id, ClientId::Local(_)))
+        .collect();
+    let mut sent = std::mem::take(&mut world.resource_mut::<SentScenes>().0);
+    sent.retain(|client_id, _| clients.contains(client_id));
+    let hashes = world.resource::<SceneEntityHashes>();
+    let baseline = hashes.baseline();
+    let full_len: usize = hashes.0.values().map(|scene_entity| scene_entity.len).sum();
+    // clients are usually missing the same changes, serialize them once
+    let mut upserts: HashMap<Vec<Entity>, String> = HashMap::default();
+    let mut messages = Vec::new();
+    let mut snapshots = Vec::new();
+    for &client_id in &clients {
+        let Some(last) = sent.get_mut(&client_id) else {
+            snapshots.push(client_id);
+            continue;
+        };
+        let mut changed: Vec<Entity> = hashes
+            .0
+            .iter()
+            .filter(|(net_id, scene_entity)| last.get(*net_id) != Some(&scene_entity.hash))
+            .map(|(_, scene_entity)| scene_entity.entity)
+            .collect();
+        let removed: Vec<NetId> = last
+            .keys()
+            .filter(|net_id| !hashes.0.contains_key(*net_id))
+            .copied()
+            .collect();
+        if changed.is_empty() && removed.is_empty() {
+            continue;
+        }
+        changed.sort();
+        let upserts = upserts
+            .entry(changed)
+            .or_insert_with_key(|changed| serialize(world, changed))
+            .clone();
+        let delta_len = upserts.len() + removed.len() * size_of::<NetId>();
+        if delta_len > full_len {
+            snapshots.push(client_id);
+            continue;
+        }
+        messages.push((client_id, SceneDelta { upserts, removed }));
+        *last = baseline.clone();
+    }
+    drop(type_registry);
+    world.resource_mut::<SentScenes>().0 = sent;
+
+    for (client_id, mut delta) in messages {
+        let result = world
+            .resource_mut::<ConnectionManager>()
+            .send_message::<Channel1, _>(client_id, &mut delta);
+        if let Err(err) = result {
+            error!(
+                "Failed to send the scene changes to {:?}: {:?}",
+                client_id, err
+            );
+        }
+    }
+    // fragmented like any other snapshot when large
+    for client_id in snapshots {
+        send_scene_snapshot(world, client_id);
+    }
+}
+
+/// Send the scene to every client that connects, see [`send_scene_snapshot`]. The local
+/// client of a host server is skipped, it already sees the server world
+fn send_scene_snapshots(
+    world: &mut World,
+    accepted_events: &mut SystemState<EventReader<ClientAccepted>>,
+) {
+    let clients: Vec<ClientId> = accepted_events
+        .get_mut(world)
+        .read()
+        .map(|event| event.client_id)
+        .filter(|client_id| !matches!(client_id, ClientId::Local(_)))
+        .collect();
+    for client_id in clients {
+        send_scene_snapshot(world, client_id);
+    }
+}
+
+/// Answer the [`RequestSceneSnapshot`]s with the current scene, at most once per
+/// [`SCENE_REQUEST_COOLDOWN`] for each client
+fn answer_scene_requests(
+    world: &mut World,
+    request_events: &mut SystemState<EventReader<ServerMessageEvent<RequestSceneSnapshot>>>,
+    mut last_answered: Local<HashMap<ClientId, Duration>>,
+) {
+    let now = world.resource::<Time>().elapsed();
+    let clients: Vec<ClientId> = request_events
+        .get_mut(world)
+        .read()
+        .map(|event| *event.context())
+        .collect();
+    for client_id in clients {
+        if let Some(last) = last_answered.get(&client_id) {
+            if now - *last < SCENE_REQUEST_COOLDOWN {
+                debug!("Ignoring the scene request of {:?}, too soon", client_id);
+                continue;
+            }
+        }
+        last_answered.insert(client_id, now);
+        send_scene_snapshot(world, client_id);
+    }
+}
+
+/// Send the chat messages of every client to all the clients
+fn broadcast_chat(
+    mut connection: ResMut<ConnectionManager>,
+    mut chat_events: EventReader<ServerMessageEvent<ChatMessage>>,
+) {
+    for event in chat_events.read() {
+        let mut message = event.message().clone();
+        message.sender = *event.context();
+        message.truncate();
+        info!("[chat] {:?}: {}", message.sender, message.text);
+        if let Err(err) =
+            connection.send_message_to_target::<Channel1, _>(&mut message, NetworkTarget::All)
+        {
+            error!("Failed to broadcast a chat message: {:?}", err);
+        }
+    }
+}
+
+/// Rename the entity carried by a client as it asks, when the name is valid. The new `Name`
+/// is then replicated to everyone
+fn apply_rename_requests(
+    index: Res<CarrierIndex>,
+    mut names: Query<&mut Name>,
+    mut rename_events: EventReader<ServerMessageEvent<RenameRequest>>,
+) {
+    for event in rename_events.read() {
+        let client_id = *event.context();
+        let new_name = match event.message().validated() {
+            Ok(name) => name,
+            Err(reason) => {
+                warn!("Refusing the rename from {:?}: {}", client_id, reason);
+                continue;
+            }
+        };
+        let Some(entity) = index.entity_for_client(client_id) else {
+            continue;
+        };
+        if let Ok(mut name) = names.get_mut(entity) {
+            info!("{:?} renamed its entity to {:?}", client_id, new_name);
+            name.set(new_name.to_string());
+        }
+    }
+}
+
+/// Add one to the `ComponentA` of every replicated entity once per
+/// [`IncrementConfig::interval`], except in the [`PausedRooms`] and for the entities a client
+/// has the authority over
+fn increment_component_a(
+    time: Res<Time>,
+    config: Res<IncrementConfig>,
+    lobby_config: Res<LobbyConfig>,
+    persisted: Res<PersistedRooms>,
+    paused_rooms: Res<PausedRooms>,
+    mut timer: Local<Option<Timer>>,
+    mut query: Query<(Entity, &CarrierId, &mut ComponentA), (With<Replicated>, With<HasAuthority>)>,
+) {
+    let Some(interval) = config.interval else {
+        return;
+    };
+    let timer = timer.get_or_insert_with(|| Timer::new(interval, TimerMode::Repeating));
+    timer.set_duration(interval);
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (entity, carrier_id, mut component_a) in query.iter_mut() {
+        // the entity is in the room its client was transferred to, not the default one
+        if lobby_config.use_rooms
+            && paused_rooms
+                .0
+                .contains(&persisted.room_of(carrier_id.0, &lobby_config))
+        {
+            continue;
+        }
+        component_a.0 += 1;
+        debug!("ComponentA of {:?} is now {}", entity, component_a.0);
+    }
+}
+
+/// Put the received inputs in the [`InputBuffer`] under the tick they are for. Inputs for a
+/// tick already simulated are too late and dropped, as are those too far ahead.
+///
+/// The local client of a host server shares our tick, so its inputs always look late: they
+/// are applied on the next tick instead
+fn buffer_player_input(
+    tick: Res<CurrentTick>,
+    mut buffer: ResMut<InputBuffer>,
+    mut input_events: EventReader<ServerMessageEvent<PlayerInput>>,
+    mut disconnect_events: EventReader<ServerDisconnectEvent>,
+) {
+    for event in input_events.read() {
+        let input = event.message();
+        let input_tick = match *event.context() {
+            ClientId::Local(_) => Tick(tick.0 .0.wrapping_add(1)),
+            _ => input.tick,
+        };
+        let lead = input_tick.0.wrapping_sub(tick.0 .0) as i16;
+        if lead <= 0 || lead > INPUT_BUFFER_TICKS {
+            debug!(
+                "Dropping the input of {:?} for tick {} at tick {}",
+                *event.context(),
+                input_tick.0,
+                tick.0 .0
+            );
+            continue;
+        }
+        buffer
+            .inputs
+            .insert((*event.context(), input_tick), input.clone());
+    }
+    for event in disconnect_events.read() {
+        buffer
+            .inputs
+            .retain(|(client_id, _), _| *client_id != event.client_id);
+        buffer.last.remove(&event.client_id);
+    }
+}
+
+/// Move the entity carried by each client by its input for the current tick, one tick worth
+/// of movement. A client without an input for this tick (lost, or late) keeps doing what its
+/// last input said, for [`MAX_REPEATED_INPUT_TICKS`] at most
+fn apply_player_input(
+    config: Res<ServerConfig>,
+    tick: Res<CurrentTick>,
+    index: Res<CarrierIndex>,
+    mut buffer: ResMut<InputBuffer>,
+    mut players: Query<&mut NetPosition, With<ComponentA>>,
+) {
+    let tick_secs = config.shared.tick.tick_duration.as_secs_f32();
+    let InputBuffer { inputs, last } = &mut *buffer;
+    inputs.retain(|(client_id, input_tick), input| {
+        let lead = input_tick.0.wrapping_sub(tick.0 .0) as i16;
+        if lead == 0 {
+            last.insert(*client_id, (*input_tick, input.clone()));
+        }
+        lead > 0
+    });
+    last.retain(|_, (input_tick, _)| {
+        tick.0 .0.wrapping_sub(input_tick.0) <= MAX_REPEATED_INPUT_TICKS
+    });
+    for (client_id, (_, input)) in last.iter() {
+        let Some(entity) = index.entity_for_client(*client_id) else {
+            continue;
+        };
+        if let Ok(mut position) = players.get_mut(entity) {
+            position.0 += input.direction.extend(0.0) * PLAYER_SPEED * tick_secs;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{protocol_registry, Stepper};
+    use bevy::ecs::system::RunSystemOnce;
+
+    /// A component that isn't registered with `register_type` is silently left out of saved
+    /// scenes, this catches it before a scene comes back incomplete
+    #[test]
+    fn scene_roundtrip() {
+        let app_type_registry = protocol_registry();
+        let client_id = ClientId::Netcode(0);
+        let mut scene_world = build_scene_world(&app_type_registry, client_id);
+        let ron = serialize_current_scene(&mut scene_world).unwrap();
+
+        let scene = parse_ron_scene(&ron, &app_type_registry).unwrap();
+        let mut loaded_world = World::new();
+        loaded_world.insert_resource(app_type_registry);
+        scene
+            .write_to_world(&mut loaded_world, &mut default())
+            .unwrap();
+
+        let (component_a, component_b, carrier_id, name) = loaded_world
+            .query::<(&ComponentA, &ComponentB, &CarrierId, &Name)>()
+            .single(&loaded_world);
+        assert_eq!(*component_a, ComponentA(2));
+        assert_eq!(*component_b, ComponentB(0.5));
+        assert_eq!(*carrier_id, CarrierId(client_id));
+        assert_eq!(name.as_str(), "Replicated entity");
+    }
+
+    /// Split the snapshot of a world with many `ComponentA` entities into [`Fragment`]s, put
+    /// it back together in reverse order and check it came back intact. Over [`Channel1`] the
+    /// fragments arrive in order, this also covers losing the order on the way
+    #[test]
+    fn fragment_roundtrip() {
+        let mut world = World::new();
+        world.insert_resource(protocol_registry());
+        world.spawn_batch((0..10_000).map(ComponentA));
+        let data = save_snapshot(&world).unwrap();
+        let fragments = split_into_fragments(0, &data, FragmentConfig::default().fragment_size);
+        assert!(fragments.len() > 1);
+
+        let mut reassembler = crate::shared::FragmentReassembler::default();
+        let mut reassembled = None;
+        for fragment in fragments.into_iter().rev() {
+            assert!(reassembled.is_none());
+            reassembled = reassembler.push(fragment).unwrap();
+        }
+        assert_eq!(reassembled, Some(data));
+    }
+
+    /// The confirmed player entities replicated to a client
+    fn replicated_players(world: &mut World) -> usize {
+        world
+            .query_filtered::<(), (With<ComponentA>, With<CarrierId>, With<client::Confirmed>)>()
+            .iter(world)
+            .count()
+    }
+
+    #[test]
+    fn clients_on_two_endpoints_get_replicated() {
+        let mut stepper = Stepper::with_server(&[1, 1], |server| {
+            server.insert_resource(LobbyConfig {
+                use_rooms: false,
+                ..default()
+            });
+        });
+        assert_eq!(
+            stepper.server.world().resource::<ConnectedClients>().len(),
+            2
+        );
+        let replicated = stepper.step_until(100, |stepper| {
+            (0..2).all(|client| replicated_players(stepper.client_world(client)) > 0)
+        });
+        assert!(
+            replicated,
+            "both clients should get the player of the scene"
+        );
+    }
+
+    /// The parts of the players replicated to a client, with the parent lightyear rebuilt
+    /// from their `ParentSync`
+    fn replicated_parts(world: &mut World) -> Vec<(Entity, Option<Entity>)> {
+        world
+            .query_filtered::<(Entity, Option<&Parent>), (
+                With<ComponentA>,
+                With<ParentSync>,
+                Without<CarrierId>,
+                With<client::Confirmed>,
+            )>()
+            .iter(world)
+            .map(|(entity, parent)| (entity, parent.map(Parent::get)))
+            .collect()
+    }
+
+    /// The player and its part are in one replication group, the client never sees a part
+    /// whose player isn't there yet
+    #[test]
+    fn part_never_arrives_without_its_player() {
+        let mut stepper = Stepper::new(&[1]);
+        let arrived = stepper.step_until(100, |stepper| {
+            let world = stepper.client_world(0);
+            let parts = replicated_parts(world);
+            for (part, parent) in &parts {
+                let parent = parent.unwrap_or_else(|| panic!("part {part} has no parent"));
+                assert!(
+                    world.get::<CarrierId>(parent).is_some(),
+                    "part {part} arrived before its player"
                 );
-                commands.entity(entity).insert(replicate).with_child(ComponentA(0));
-            } else {
-                let replicate = Replicate {
-                    target: ReplicationTarget {
-                        target: NetworkTarget::All,
-                    },
-                    ..default()
-                };
-                info!("Started to replicate entity {} with component A", entity);
-                commands.entity(entity).insert(replicate);
+            }
+            !parts.is_empty()
+        });
+        assert!(arrived, "the part of the player wasn't replicated");
+    }
+
+    /// On the client, the part is a child of the player it was spawned under on the server
+    #[test]
+    fn part_has_its_player_as_parent() {
+        let mut stepper = Stepper::new(&[1]);
+        let arrived = stepper.step_until(100, |stepper| {
+            !replicated_parts(stepper.client_world(0)).is_empty()
+        });
+        assert!(arrived, "the part of the player wasn't replicated");
+
+        let world = stepper.client_world(0);
+        let player = world
+            .query_filtered::<Entity, (With<CarrierId>, With<client::Confirmed>)>()
+            .single(world);
+        let parts = replicated_parts(world);
+        assert_eq!(parts.len(), 1);
+        let (part, parent) = parts[0];
+        assert_eq!(parent, Some(player));
+        assert!(world
+            .get::<Children>(player)
+            .is_some_and(|children| children.contains(&part)));
+    }
+
+    /// Every client gets exactly one entity per connected player, and the player parts come
+    /// as their children, never as entities of their own
+    #[test]
+    fn one_entity_per_client() {
+        let mut stepper = Stepper::with_server(&[2], |server| {
+            server.insert_resource(LobbyConfig {
+                use_rooms: false,
+                ..default()
+            });
+            // the default scene only has the player of the first client
+            server
+                .world_mut()
+                .spawn((CarrierId(ClientId::Netcode(1)), ComponentA(0)));
+        });
+        let replicated = stepper.step_until(100, |stepper| {
+            (0..2).all(|client| replicated_players(stepper.client_world(client)) == 2)
+        });
+        assert!(replicated, "the clients didn't get both players");
+        // a few more replication intervals, nothing else should show up
+        for _ in 0..50 {
+            stepper.step();
+        }
+
+        for client in 0..2 {
+            let world = stepper.client_world(client);
+            let mut carriers = world
+                .query_filtered::<&CarrierId, With<client::Confirmed>>()
+                .iter(world)
+                .map(|carrier_id| carrier_id.0)
+                .collect::<Vec<_>>();
+            carriers.sort_by_key(ClientId::to_bits);
+            assert_eq!(carriers, [ClientId::Netcode(0), ClientId::Netcode(1)]);
+
+            let entities = world
+                .query_filtered::<(), (With<ComponentA>, With<client::Confirmed>)>()
+                .iter(world)
+                .count();
+            let parts = replicated_parts(world);
+            assert_eq!(parts.len(), 2);
+            assert_eq!(entities, 4);
+            assert!(parts.iter().all(|(_, parent)| parent.is_some()));
+        }
+    }
+
+    /// Removing `ComponentA` from a player on the server removes it from the client's copies,
+    /// the confirmed one and the predicted one
+    #[test]
+    fn component_removal_is_replicated() {
+        let mut stepper = Stepper::new(&[1]);
+        let replicated = stepper.step_until(100, |stepper| {
+            replicated_players(stepper.client_world(0)) == 1
+        });
+        assert!(replicated, "the player wasn't replicated");
+
+        let server_world = stepper.server_world();
+        let player = server_world
+            .query_filtered::<Entity, (With<CarrierId>, With<Replicated>)>()
+            .single(server_world);
+        server_world.entity_mut(player).remove::<ComponentA>();
+
+        let removed = stepper.step_until(100, |stepper| {
+            let world = stepper.client_world(0);
+            world
+                .query_filtered::<(), (
+                    With<CarrierId>,
+                    With<ComponentA>,
+                    Or<(With<client::Confirmed>, With<client::Predicted>)>,
+                )>()
+                .iter(world)
+                .next()
+                .is_none()
+        });
+        assert!(removed, "ComponentA is still on the client");
+        let world = stepper.client_world(0);
+        assert_eq!(
+            world
+                .query_filtered::<(), (With<CarrierId>, With<client::Confirmed>)>()
+                .iter(world)
+                .count(),
+            1,
+            "the player itself should stay"
+        );
+    }
+
+    /// A client whose inputs stop arriving, its release included, stops moving after
+    /// [`MAX_REPEATED_INPUT_TICKS`] instead of moving on forever
+    #[test]
+    fn lost_inputs_are_repeated_a_few_ticks_only() {
+        let mut stepper = Stepper::new(&[1]);
+        let client_id = ClientId::Netcode(0);
+        let replicated = stepper.step_until(100, |stepper| {
+            let world = stepper.server_world();
+            world
+                .resource::<CarrierIndex>()
+                .entity_for_client(client_id)
+                .is_some_and(|entity| world.get::<NetPosition>(entity).is_some())
+        });
+        assert!(replicated, "the player wasn't replicated");
+        let world = stepper.server_world();
+        let player = world
+            .resource::<CarrierIndex>()
+            .entity_for_client(client_id)
+            .unwrap();
+        let next_tick = Tick(world.resource::<CurrentTick>().0 .0.wrapping_add(1));
+        world.resource_mut::<InputBuffer>().inputs.insert(
+            (client_id, next_tick),
+            PlayerInput {
+                tick: next_tick,
+                direction: Vec2::X,
+            },
+        );
+
+        let position =
+            |stepper: &mut Stepper| stepper.server_world().get::<NetPosition>(player).unwrap().0;
+        let start = position(&mut stepper);
+        for _ in 0..MAX_REPEATED_INPUT_TICKS * 4 {
+            stepper.step();
+        }
+        let stopped = position(&mut stepper);
+        assert!(stopped.x > start.x, "the input wasn't applied");
+        let max_travel = PLAYER_SPEED
+            * crate::shared::shared_config()
+                .tick
+                .tick_duration
+                .as_secs_f32()
+            * (MAX_REPEATED_INPUT_TICKS + 1) as f32;
+        assert!(stopped.x - start.x <= max_travel + 1e-3);
+        for _ in 0..10 {
+            stepper.step();
+        }
+        assert_eq!(position(&mut stepper), stopped);
+    }
+
+    /// Step until the scene writes running in the background are done, and read `path`
+    fn read_saved_scene(stepper: &mut Stepper, path: &Path) -> String {
+        // let the server queue the writes of the events sent in the meantime
+        stepper.step();
+        let saved = stepper.step_until(200, |stepper| {
+            stepper
+                .server_world()
+                .resource::<PendingSceneSaves>()
+                .0
+                .is_empty()
+        });
+        assert!(saved, "the scene wasn't saved");
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    /// A save with the key overwrites the scene written on connect, the next connect must
+    /// write its scene again rather than take it for unchanged
+    #[test]
+    fn connect_saves_again_after_a_key_save() {
+        let mut stepper = Stepper::new(&[1]);
+        let path = stepper
+            .server
+            .world()
+            .resource::<SceneSaveConfig>()
+            .path_for(None);
+        let on_connect = read_saved_scene(&mut stepper, &path);
+
+        let world = stepper.server_world();
+        let save_key = world.resource::<SceneSaveConfig>().save_key;
+        world
+            .get_resource_or_init::<ButtonInput<KeyCode>>()
+            .press(save_key);
+        world.run_system_once(save_scene_on_key).unwrap();
+        world.resource_mut::<ButtonInput<KeyCode>>().reset(save_key);
+        assert_ne!(read_saved_scene(&mut stepper, &path), on_connect);
+
+        stepper.server.world_mut().send_event(ServerConnectEvent {
+            client_id: ClientId::Netcode(0),
+            entity: Entity::PLACEHOLDER,
+        });
+        assert_eq!(read_saved_scene(&mut stepper, &path), on_connect);
+    }
+
+    /// A client carrying two entities has both indexed, and both are kept for its return and
+    /// taken out of its room when it disconnects
+    #[test]
+    fn disconnect_handles_every_carried_entity() {
+        let client_id = ClientId::Netcode(0);
+        let mut stepper = Stepper::with_server(&[1], |server| {
+            server
+                .world_mut()
+                .spawn((CarrierId(ClientId::Netcode(0)), ComponentA(0)));
+        });
+        let indexed = stepper.step_until(100, |stepper| {
+            stepper
+                .server_world()
+                .resource::<CarrierIndex>()
+                .entities_for_client(client_id)
+                .len()
+                == 2
+        });
+        assert!(indexed, "both entities should be indexed");
+
+        stepper.clients[0]
+            .world_mut()
+            .run_system_once(|mut commands: Commands| {
+                client::ClientCommands::disconnect_client(&mut commands)
+            })
+            .unwrap();
+        let disconnected = stepper.step_until(100, |stepper| {
+            stepper
+                .server_world()
+                .resource::<ConnectedClients>()
+                .is_empty()
+        });
+        assert!(disconnected, "the client didn't disconnect");
+        stepper.step();
+
+        let world = stepper.server_world();
+        let entities = world
+            .resource::<CarrierIndex>()
+            .entities_for_client(client_id)
+            .to_vec();
+        let room_id = world.resource::<LobbyConfig>().room_for(client_id);
+        let rooms = world.resource::<RoomManager>();
+        let room_entities = rooms
+            .get_room(room_id)
+            .map(|room| room.entities.clone())
+            .unwrap_or_default();
+        assert_eq!(entities.len(), 2);
+        for entity in entities {
+            assert!(world.get::<AwaitingReconnect>(entity).is_some());
+            assert!(!room_entities.contains(&entity));
+        }
+    }
+
+    /// A banned client is refused by Netcode: it never connects, its entity isn't
+    /// replicated and it doesn't get a room
+    #[test]
+    fn banned_client_is_not_set_up() {
+        let banned = ClientId::Netcode(1);
+        let mut entity = Entity::PLACEHOLDER;
+        let mut stepper = Stepper::starting(&[2], |server| {
+            server
+                .world_mut()
+                .resource_mut::<BanList>()
+                .ban_client(banned);
+            entity = server
+                .world_mut()
+                .spawn((CarrierId(banned), ComponentA(0)))
+                .id();
+            record_events::<ConnectionRejected>(server);
+        });
+        let synced = stepper.step_until(500, |stepper| stepper.is_synced(0));
+        assert!(synced, "the other client should connect");
+        for _ in 0..50 {
+            stepper.step();
+        }
+
+        assert!(!stepper.is_synced(1));
+        let world = stepper.server_world();
+        assert!(!world.resource::<ConnectedClients>().contains(banned));
+        let rejected = &world.resource::<Received<ConnectionRejected>>().0;
+        assert!(!rejected.is_empty());
+        for event in rejected {
+            assert_eq!(event.client_id, banned);
+            assert_eq!(event.reason, DeniedReason::Banned);
+        }
+        assert!(world.get::<Replicated>(entity).is_none());
+        assert!(!world.resource::<PersistedRooms>().0.contains_key(&banned));
+    }
+
+    /// With room for one client, the second connection request is refused by Netcode: that
+    /// client never connects, and a ConnectionRejected says why
+    #[test]
+    fn server_full_refuses_the_connection() {
+        let mut stepper = Stepper::starting(&[2], |server| {
+            server.insert_resource(ConnectionLimits {
+                max_clients: 1,
+                ..default()
+            });
+            record_events::<ConnectionRejected>(server);
+        });
+        let synced =
+            stepper.step_until(500, |stepper| stepper.is_synced(0) || stepper.is_synced(1));
+        assert!(synced, "one client should connect");
+        for _ in 0..50 {
+            stepper.step();
+        }
+
+        let world = stepper.server_world();
+        let connected: Vec<ClientId> = world.resource::<ConnectedClients>().iter().collect();
+        assert_eq!(connected.len(), 1);
+        let rejected = &world.resource::<Received<ConnectionRejected>>().0;
+        assert!(!rejected.is_empty());
+        for event in rejected {
+            assert_ne!(event.client_id, connected[0]);
+            assert_eq!(event.reason, DeniedReason::ServerFull);
+        }
+    }
+
+    /// A tick rate that isn't a positive number falls back to the default one instead of
+    /// panicking when the tick duration is computed
+    #[test]
+    fn invalid_tick_hz_falls_back() {
+        let fallback = ServerSettings::default().tick_duration();
+        for hz in [0.0, -30.0, f64::NAN, f64::INFINITY] {
+            let settings = ServerSettings {
+                tick_hz: hz,
+                ..default()
             };
-        }   
+            assert_eq!(settings.tick_duration(), fallback);
+        }
+    }
+
+    /// A client transferred to another room is taken out of that room when it disconnects,
+    /// and put back in it with its entity when it reconnects
+    #[test]
+    fn transferred_client_keeps_its_room_across_reconnects() {
+        let client_id = ClientId::Netcode(0);
+        let to = RoomId(42);
+        let mut stepper = Stepper::new(&[1]);
+        stepper.server_world().send_event(TransferClientRequest {
+            client_id,
+            from: room_for_client(client_id),
+            to,
+        });
+        let transferred = stepper.step_until(10, |stepper| {
+            let world = stepper.server_world();
+            let persisted = world.resource::<PersistedRooms>();
+            persisted.room_of(client_id, world.resource::<LobbyConfig>()) == to
+        });
+        assert!(transferred);
+        let entity = stepper
+            .server_world()
+            .resource::<CarrierIndex>()
+            .entity_for_client(client_id)
+            .unwrap();
+        // whether the client and its entity are in `room_id`
+        let in_room = |stepper: &mut Stepper, room_id: RoomId| {
+            stepper
+                .server_world()
+                .resource::<RoomManager>()
+                .get_room(room_id)
+                .map_or((false, false), |room| {
+                    (
+                        room.clients.contains(&client_id),
+                        room.entities.contains(&entity),
+                    )
+                })
+        };
+        assert_eq!(in_room(&mut stepper, to), (true, true));
+
+        stepper.clients[0]
+            .world_mut()
+            .run_system_once(|mut commands: Commands| {
+                client::ClientCommands::disconnect_client(&mut commands)
+            })
+            .unwrap();
+        let disconnected = stepper.step_until(100, |stepper| {
+            stepper
+                .server_world()
+                .resource::<ConnectedClients>()
+                .is_empty()
+        });
+        assert!(disconnected, "the client didn't disconnect");
+        stepper.step();
+        assert_eq!(in_room(&mut stepper, to), (false, false));
+
+        stepper.clients[0]
+            .world_mut()
+            .run_system_once(|mut commands: Commands| {
+                client::ClientCommands::connect_client(&mut commands)
+            })
+            .unwrap();
+        let reconnected = stepper.step_until(500, |stepper| stepper.is_synced(0));
+        assert!(reconnected, "the client didn't reconnect");
+        stepper.step();
+        assert_eq!(in_room(&mut stepper, to), (true, true));
+        assert_eq!(
+            in_room(&mut stepper, room_for_client(client_id)),
+            (false, false)
+        );
+    }
+
+    /// A transfer out of a room the client isn't in is refused, and the saved room stays
+    /// the one the client is in
+    #[test]
+    fn refused_transfer_keeps_the_saved_room() {
+        let client_id = ClientId::Netcode(0);
+        let mut stepper = Stepper::new(&[1]);
+        stepper.server_world().send_event(TransferClientRequest {
+            client_id,
+            from: RoomId(41),
+            to: RoomId(42),
+        });
+        for _ in 0..10 {
+            stepper.step();
+        }
+        let world = stepper.server_world();
+        let persisted = world.resource::<PersistedRooms>();
+        assert_eq!(
+            persisted.room_of(client_id, world.resource::<LobbyConfig>()),
+            room_for_client(client_id)
+        );
+    }
+
+    /// Pausing the room a client was transferred to freezes its entity, pausing the room it
+    /// left doesn't
+    #[test]
+    fn paused_room_follows_transfers() {
+        let client_id = ClientId::Netcode(0);
+        let to = RoomId(42);
+        let mut stepper = Stepper::with_server(&[1], |server| {
+            server.insert_resource(IncrementConfig {
+                interval: Some(Duration::from_millis(100)),
+            });
+        });
+        stepper.server_world().send_event(TransferClientRequest {
+            client_id,
+            from: room_for_client(client_id),
+            to,
+        });
+        let transferred = stepper.step_until(10, |stepper| {
+            let world = stepper.server_world();
+            let persisted = world.resource::<PersistedRooms>();
+            persisted.room_of(client_id, world.resource::<LobbyConfig>()) == to
+        });
+        assert!(transferred);
+
+        let component_a = |stepper: &mut Stepper| {
+            let world = stepper.server_world();
+            let entity = world
+                .resource::<CarrierIndex>()
+                .entity_for_client(client_id)
+                .unwrap();
+            world.get::<ComponentA>(entity).unwrap().0
+        };
+        stepper.server_world().resource_mut::<PausedRooms>().0 = HashSet::from([to]);
+        let before = component_a(&mut stepper);
+        for _ in 0..20 {
+            stepper.step();
+        }
+        assert_eq!(component_a(&mut stepper), before);
+
+        stepper.server_world().resource_mut::<PausedRooms>().0 =
+            HashSet::from([room_for_client(client_id)]);
+        for _ in 0..20 {
+            stepper.step();
+        }
+        assert!(component_a(&mut stepper) > before);
+    }
+
+    /// Every event of type `E` the server sent, see [`record_events`]
+    #[derive(Resource)]
+    struct Received<E: Event>(Vec<E>);
+
+    /// Keep every `E` the server sends in [`Received`], to be added in the setup of a
+    /// [`Stepper`]
+    fn record_events<E: Event + Clone>(server: &mut App) {
+        server.insert_resource(Received::<E>(Vec::new()));
+        server.add_systems(
+            Last,
+            |mut events: EventReader<E>, mut received: ResMut<Received<E>>| {
+                received.0.extend(events.read().cloned());
+            },
+        );
+    }
+
+    /// Two clients carrying two entities each connect to a shared room with space for one.
+    /// The client turned away gets a single RoomFull, not one per entity or per frame
+    #[test]
+    fn room_full_is_sent_once_per_connect() {
+        let mut stepper = Stepper::with_server(&[2], |server| {
+            server.insert_resource(LobbyConfig {
+                shared_room: Some(RoomRegistry::LOBBY),
+                capacity: 1,
+                ..default()
+            });
+            server.world_mut().spawn_batch([
+                (CarrierId(ClientId::Netcode(0)), ComponentA(0)),
+                (CarrierId(ClientId::Netcode(1)), ComponentA(0)),
+                (CarrierId(ClientId::Netcode(1)), ComponentA(0)),
+            ]);
+            record_events::<RoomFull>(server);
+        });
+        for _ in 0..20 {
+            stepper.step();
+        }
+
+        let room_full = &stepper.server_world().resource::<Received<RoomFull>>().0;
+        assert_eq!(room_full.len(), 1, "{:?}", room_full);
+        assert_eq!(room_full[0].room_id, RoomRegistry::LOBBY);
+    }
+
+    /// Once the lobby has enough players a single game is started with them, the next frames
+    /// don't start it again
+    #[test]
+    fn match_is_started_once() {
+        let mut stepper = Stepper::with_server(&[2], |server| {
+            server.insert_resource(LobbyConfig {
+                shared_room: Some(RoomRegistry::LOBBY),
+                ..default()
+            });
+            server
+                .world_mut()
+                .spawn((CarrierId(ClientId::Netcode(1)), ComponentA(0)));
+            record_events::<MatchStarted>(server);
+        });
+        for _ in 0..20 {
+            stepper.step();
+        }
+
+        let world = stepper.server_world();
+        let started = &world.resource::<Received<MatchStarted>>().0;
+        assert_eq!(started.len(), 1, "{:?}", started);
+        assert_eq!(world.resource::<Matchmaker>().games, 1);
+    }
+
+    /// Certificates are read back from the PEM files they were written to, and a missing
+    /// file is an error rather than a panic
+    #[test]
+    fn certificate_is_loaded_from_pem_files() {
+        let dir = std::env::temp_dir().join(format!("mre_scene_pem_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        let identity = Identity::self_signed(["localhost"]).unwrap();
+        let certificate = &identity.certificate_chain().as_slice()[0];
+        std::fs::write(&cert, certificate.to_pem()).unwrap();
+        std::fs::write(&key, identity.private_key().to_secret_pem()).unwrap();
+
+        let loaded = load_pemfiles(&cert, &key).unwrap();
+        assert_eq!(
+            loaded.certificate_chain().as_slice()[0].hash(),
+            certificate.hash()
+        );
+        assert!(load_pemfiles(&dir.join("missing.pem"), &key).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Every unregistered component type of a scene is found, not only the first one, and
+    /// the registered ones are left out
+    #[test]
+    fn unregistered_types_are_listed() {
+        let ron = DEFAULT_SCENE_RON.replace(
+            r#""mre_scene::shared::ComponentB": (0.5),"#,
+            r#""mre_scene::shared::ComponentB": (0.5),
+        "game::Missing": (speed: 2.0, kind: Fast(3)),
+        "game::AlsoMissing": Some([1, 2]),"#,
+        );
+        assert_ne!(ron, DEFAULT_SCENE_RON);
+        let registry = protocol_registry();
+        assert_eq!(
+            unregistered_types_in_ron(&ron, &registry.read()),
+            vec!["game::AlsoMissing".to_string(), "game::Missing".to_string()]
+        );
+        assert!(unregistered_types_in_ron(DEFAULT_SCENE_RON, &registry.read()).is_empty());
+    }
+
+    /// Backups made within the same second get their own file, and the oldest ones go first
+    /// whether they were named with or without a sequence number
+    #[test]
+    fn backups_made_in_the_same_second_are_kept() {
+        let dir = std::env::temp_dir().join(format!("mre_scene_backups_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scene.ron");
+        // named the way backups were before the sequence number
+        let legacy = dir.join("scene.1.ron.bak");
+        std::fs::write(&legacy, "legacy").unwrap();
+        for save in 0..3 {
+            std::fs::write(&path, format!("save {save}")).unwrap();
+            backup_scene_file(&path, 3).unwrap();
+        }
+
+        let mut backups: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        backups.sort();
+        assert_eq!(backups, ["save 0", "save 1", "save 2"]);
+        assert!(!legacy.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// What a despawned player had is forgotten once `forget_after` has passed, so clients
+    /// that never come back don't stay in memory
+    #[test]
+    fn despawned_players_are_forgotten() {
+        let client_id = ClientId::Netcode(0);
+        let forget_after = Duration::from_millis(300);
+        let mut stepper = Stepper::with_server(&[1], |server| {
+            server.insert_resource(ConnectionLimits {
+                reconnect_grace: Duration::ZERO,
+                forget_after,
+                ..default()
+            });
+        });
+        stepper.clients[0]
+            .world_mut()
+            .run_system_once(|mut commands: Commands| {
+                client::ClientCommands::disconnect_client(&mut commands)
+            })
+            .unwrap();
+        let remembered = stepper.step_until(100, |stepper| {
+            stepper
+                .server_world()
+                .resource::<DespawnedPlayers>()
+                .0
+                .contains_key(&client_id)
+        });
+        assert!(remembered, "the player wasn't despawned");
+
+        let tick = crate::shared::shared_config().tick.tick_duration;
+        let frames = (forget_after.as_secs_f64() / tick.as_secs_f64()) as usize + 2;
+        for _ in 0..frames {
+            stepper.step();
+        }
+        assert!(stepper
+            .server_world()
+            .resource::<DespawnedPlayers>()
+            .0
+            .is_empty());
     }
 }
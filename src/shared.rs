@@ -1,58 +1,943 @@
 //! This module contains the shared code between the client and the server.
 
-use bevy::utils::Duration;
+use bevy::log::{Level, LogPlugin};
+use bevy::reflect::GetTypeRegistration;
+use bevy::scene::serde::SceneDeserializer;
+use bevy::utils::{Duration, HashMap};
 use bevy::{prelude::*, reflect};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::de::{DeserializeOwned, DeserializeSeed};
+use std::collections::VecDeque;
+use std::fmt;
+use std::marker::PhantomData;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 
+use lightyear::prelude::client::ComponentSyncMode;
 use lightyear::prelude::*;
 use lightyear::shared::config::Mode;
 
 pub const FIXED_TIMESTEP_HZ: f64 = 64.0;
 
+/// Environment variable overriding [`FIXED_TIMESTEP_HZ`], e.g. `MRE_TICK_HZ=30`
+pub const TICK_HZ_ENV: &str = "MRE_TICK_HZ";
+
 pub const SERVER_REPLICATION_INTERVAL: Duration = Duration::from_millis(100);
 
+/// Address of the UDP transport
 pub const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
 
+/// Environment variable overriding [`SERVER_ADDR`], e.g. `MRE_SERVER_ADDR=0.0.0.0:5000`
+pub const SERVER_ADDR_ENV: &str = "MRE_SERVER_ADDR";
+
+/// The address of the UDP transport, read from [`SERVER_ADDR_ENV`] and falling back to
+/// [`SERVER_ADDR`] when it's unset or can't be parsed
+pub fn server_addr() -> SocketAddr {
+    match std::env::var(SERVER_ADDR_ENV) {
+        Ok(value) => value.parse().unwrap_or_else(|err| {
+            warn!(
+                "Invalid {} {:?} ({}), using {}",
+                SERVER_ADDR_ENV, value, err, SERVER_ADDR
+            );
+            SERVER_ADDR
+        }),
+        Err(_) => SERVER_ADDR,
+    }
+}
+
+/// Second UDP address the server listens on, to show that a server can accept clients on
+/// several endpoints at once
+pub const SECONDARY_SERVER_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5003);
+
+/// Address of the WebSocket transport (TCP), when the server enables it
+pub const WEBSOCKET_SERVER_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5001);
+
+/// Address of the WebTransport transport, when the server enables it. It runs over UDP, so
+/// it can't share the port of [`SERVER_ADDR`]
+pub const WEBTRANSPORT_SERVER_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5002);
+
+/// Netcode protocol id used when [`PROTOCOL_ID_ENV`] isn't set. Client and server must agree
+/// on it, connections with a different protocol id are refused
+pub const PROTOCOL_ID: u64 = 0;
+
+/// Environment variable overriding [`PROTOCOL_ID`]
+pub const PROTOCOL_ID_ENV: &str = "MRE_PROTOCOL_ID";
+
+/// Environment variable holding the hex encoded 32 byte Netcode private key
+pub const PRIVATE_KEY_ENV: &str = "MRE_PRIVATE_KEY";
+
+/// Environment variable holding the path of a file containing the hex encoded private key
+pub const PRIVATE_KEY_FILE_ENV: &str = "MRE_PRIVATE_KEY_FILE";
+
+/// Key file used when [`PRIVATE_KEY_FILE_ENV`] is unset. A server without a configured key
+/// writes the one it generates there, so that clients started from the same directory
+/// connect with it
+pub const DEFAULT_PRIVATE_KEY_FILE: &str = "private_key.hex";
+
+/// The Netcode protocol id, read from [`PROTOCOL_ID_ENV`] and falling back to [`PROTOCOL_ID`]
+pub fn protocol_id() -> u64 {
+    match std::env::var(PROTOCOL_ID_ENV) {
+        Ok(value) => value.parse().unwrap_or_else(|err| {
+            warn!(
+                "Invalid {} {:?} ({}), using {}",
+                PROTOCOL_ID_ENV, value, err, PROTOCOL_ID
+            );
+            PROTOCOL_ID
+        }),
+        Err(_) => PROTOCOL_ID,
+    }
+}
+
+/// The file the private key is read from, [`PRIVATE_KEY_FILE_ENV`] or
+/// [`DEFAULT_PRIVATE_KEY_FILE`]
+pub fn private_key_path() -> PathBuf {
+    std::env::var(PRIVATE_KEY_FILE_ENV)
+        .unwrap_or_else(|_| DEFAULT_PRIVATE_KEY_FILE.into())
+        .into()
+}
+
+/// The Netcode private key, read from [`PRIVATE_KEY_ENV`] or from the file at
+/// [`private_key_path`]. `None` if there is no key or it is invalid
+pub fn private_key() -> Option<Key> {
+    let hex = match std::env::var(PRIVATE_KEY_ENV) {
+        Ok(hex) => hex,
+        Err(_) => {
+            let path = private_key_path();
+            match std::fs::read_to_string(&path) {
+                Ok(hex) => hex,
+                // no key file is only worth a warning when one was asked for
+                Err(err) if std::env::var(PRIVATE_KEY_FILE_ENV).is_ok() => {
+                    warn!("Failed to read private key file {:?}: {}", path, err);
+                    return None;
+                }
+                Err(_) => return None,
+            }
+        }
+    };
+    let key = parse_hex_key(hex.trim());
+    if key.is_none() {
+        warn!("The private key must be 64 hex characters (32 bytes), ignoring it");
+    }
+    key
+}
+
+/// Decode a 32 byte key written as 64 hex characters
+pub fn parse_hex_key(hex: &str) -> Option<Key> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = Key::default();
+    for (byte, chunk) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Encode a key as hex, the format expected by [`PRIVATE_KEY_ENV`]
+pub fn key_to_hex(key: &Key) -> String {
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The tick rate, read from [`TICK_HZ_ENV`] and falling back to [`FIXED_TIMESTEP_HZ`] when
+/// it's unset or isn't a positive number
+pub fn tick_hz() -> f64 {
+    match std::env::var(TICK_HZ_ENV) {
+        Ok(value) => match value.parse::<f64>() {
+            Ok(hz) if hz > 0.0 => hz,
+            _ => {
+                warn!(
+                    "Invalid {} {:?}, using {}",
+                    TICK_HZ_ENV, value, FIXED_TIMESTEP_HZ
+                );
+                FIXED_TIMESTEP_HZ
+            }
+        },
+        Err(_) => FIXED_TIMESTEP_HZ,
+    }
+}
+
+/// Environment variables of the link conditioner: added latency and jitter in milliseconds,
+/// and the fraction of packets lost between 0 and 1, e.g. `MRE_LATENCY_MS=100 MRE_LOSS=0.05`
+pub const LATENCY_MS_ENV: &str = "MRE_LATENCY_MS";
+pub const JITTER_MS_ENV: &str = "MRE_JITTER_MS";
+pub const LOSS_ENV: &str = "MRE_LOSS";
+
+/// Simulated bad network conditions, read from [`LATENCY_MS_ENV`], [`JITTER_MS_ENV`] and
+/// [`LOSS_ENV`]. `None` when none of them is set.
+///
+/// The conditioner only delays and drops incoming packets, so set it on both the client and
+/// the server for a round trip. Latency adds up with the 100ms
+/// [`SERVER_REPLICATION_INTERVAL`]: an update can be up to `interval + latency + jitter` old
+/// when it arrives, and the interpolation delay should cover that to stay smooth.
+pub fn link_conditioner() -> Option<LinkConditionerConfig> {
+    fn read<T: std::str::FromStr + Default>(name: &str) -> Option<T> {
+        let value = std::env::var(name).ok()?;
+        Some(value.parse().unwrap_or_else(|_| {
+            warn!("Invalid {} {:?}, ignoring it", name, value);
+            T::default()
+        }))
+    }
+    let latency: Option<u64> = read(LATENCY_MS_ENV);
+    let jitter: Option<u64> = read(JITTER_MS_ENV);
+    let loss: Option<f32> = read(LOSS_ENV);
+    if latency.is_none() && jitter.is_none() && loss.is_none() {
+        return None;
+    }
+    Some(LinkConditionerConfig {
+        incoming_latency: Duration::from_millis(latency.unwrap_or_default()),
+        incoming_jitter: Duration::from_millis(jitter.unwrap_or_default()),
+        incoming_loss: loss.unwrap_or_default().clamp(0.0, 1.0),
+    })
+}
+
+/// Make the server lose the given fraction of the packets it sends through `io`, e.g. to
+/// lose replication packets and check that [`Channel1`] still delivers everything while
+/// [`Channel2`] drops data.
+///
+/// Unlike the link conditioner, which drops packets as they are received, the packets are
+/// dropped as they are sent. Only the in-process channels of the tests can be wrapped
+#[cfg(test)]
+pub fn with_packet_loss(mut io: server::IoConfig, loss: f32) -> server::IoConfig {
+    let server::ServerTransport::Channels { channels } = io.transport else {
+        panic!("Only the channel transport can lose packets");
+    };
+    io.transport = server::ServerTransport::Channels {
+        channels: channels
+            .into_iter()
+            .map(|(addr, recv, send)| (addr, recv, lossy_sender(send, loss)))
+            .collect(),
+    };
+    io
+}
+
+/// A sender forwarding to `send` all but `loss` of what it is given
+#[cfg(test)]
+fn lossy_sender(
+    send: crossbeam_channel::Sender<Vec<u8>>,
+    loss: f32,
+) -> crossbeam_channel::Sender<Vec<u8>> {
+    let (lossy_send, lossy_recv) = crossbeam_channel::unbounded::<Vec<u8>>();
+    std::thread::spawn(move || {
+        let mut rng = StdRng::from_entropy();
+        for packet in lossy_recv {
+            if rng.gen::<f32>() >= loss && send.send(packet).is_err() {
+                break;
+            }
+        }
+    });
+    lossy_send
+}
+
+/// Environment variable holding the seed of [`SharedRng`], e.g. `MRE_SEED=42`
+pub const SEED_ENV: &str = "MRE_SEED";
+
+/// The seed [`SharedRng`] was created from. Read from [`SEED_ENV`], or random and logged so
+/// that a run can be reproduced
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct RngSeed(pub u64);
+
+impl RngSeed {
+    pub fn from_env() -> Self {
+        let seed = match std::env::var(SEED_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                let seed = rand::random();
+                warn!("Invalid {} {:?} ({}), using {}", SEED_ENV, value, err, seed);
+                seed
+            }),
+            Err(_) => rand::random(),
+        };
+        info!("Random seed is {} (set {} to reuse it)", seed, SEED_ENV);
+        Self(seed)
+    }
+}
+
+/// The random number generator every system draws from instead of the thread rng, so that
+/// runs with the same [`RngSeed`] are reproducible.
+///
+/// Nothing in the game logic is random yet: spawn positions come from [`spawn_position`].
+/// The Netcode private key is generated by the OS rng on purpose, as it must not be guessable.
+#[derive(Resource)]
+pub struct SharedRng(pub StdRng);
+
+/// The tick lightyear is at, copied every `FixedUpdate` so that everything stamped with a
+/// tick (snapshots, autosaves, pings) reads it from the same place
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct CurrentTick(pub Tick);
+
+/// The tick the app is at, see [`CurrentTick`]
+pub fn current_tick(world: &World) -> Tick {
+    world.resource::<CurrentTick>().0
+}
+
+fn update_current_tick(tick_manager: Res<TickManager>, mut current: ResMut<CurrentTick>) {
+    current.0 = tick_manager.tick();
+}
+
+fn log_current_tick(time: Res<Time>, current: Res<CurrentTick>, mut timer: Local<Option<Timer>>) {
+    let timer =
+        timer.get_or_insert_with(|| Timer::new(Duration::from_secs(5), TimerMode::Repeating));
+    if timer.tick(time.delta()).just_finished() {
+        debug!("At tick {}", current.0 .0);
+    }
+}
+
+/// Environment variable setting the log level, e.g. `MRE_LOG_LEVEL=debug`. `RUST_LOG` still
+/// takes precedence, it is applied on top as a filter
+pub const LOG_LEVEL_ENV: &str = "MRE_LOG_LEVEL";
+
+/// The [`LogPlugin`] used by both the client and the server, logging at the level read from
+/// [`LOG_LEVEL_ENV`] and at [`Level::INFO`] by default
+pub fn log_plugin() -> LogPlugin {
+    let level = match std::env::var(LOG_LEVEL_ENV) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            // the logger isn't set up yet, so `warn!` would go nowhere
+            eprintln!("Invalid {} {:?}, using INFO", LOG_LEVEL_ENV, value);
+            Level::INFO
+        }),
+        Err(_) => Level::INFO,
+    };
+    LogPlugin { level, ..default() }
+}
+
 /// The [`SharedConfig`] must be shared between the `ClientConfig` and `ServerConfig`
 pub fn shared_config() -> SharedConfig {
+    shared_config_with_mode(Mode::Separate)
+}
+
+/// Same as [`shared_config`], for the given [`Mode`]. Use `Mode::HostServer` to run the
+/// client and the server in the same `App`
+pub fn shared_config_with_mode(mode: Mode) -> SharedConfig {
+    build_shared_config(mode, tick_hz())
+}
+
+/// Same as [`shared_config`], with a tick rate of `hz` instead of [`tick_hz`]
+pub fn shared_config_with_tick(hz: f64) -> SharedConfig {
+    build_shared_config(Mode::Separate, hz)
+}
+
+fn build_shared_config(mode: Mode, hz: f64) -> SharedConfig {
     SharedConfig {
         // send an update every 100ms
         server_replication_send_interval: SERVER_REPLICATION_INTERVAL,
         tick: TickConfig {
-            tick_duration: Duration::from_secs_f64(1.0 / FIXED_TIMESTEP_HZ),
+            tick_duration: Duration::from_secs_f64(1.0 / hz),
         },
-        mode: Mode::Separate,
+        mode,
+    }
+}
+
+/// Sent by the client once connected so that the server can check both sides agree on the
+/// tick rate, see [`TICK_HZ_ENV`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientHello {
+    pub tick_duration: Duration,
+}
+
+/// Sent by the client to measure the round-trip time, the server answers right away with a
+/// [`Pong`] carrying the same tick. Both go over [`Channel2`], a lost one only skips a
+/// measurement
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Ping {
+    pub sent_tick: Tick,
+    /// The last RTT the client measured, so that the server knows it too
+    pub rtt_ms: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Pong {
+    pub sent_tick: Tick,
+}
+
+/// Global score of the game. Resources aren't replicated by lightyear like components are,
+/// so the server sends the whole resource as a message on [`Channel1`] whenever it changes,
+/// and the clients overwrite theirs with it
+#[derive(Resource, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GameScore(pub u32);
+
+/// Longest chat message in characters, the server cuts longer ones
+pub const MAX_CHAT_LENGTH: usize = 256;
+
+/// A line of chat. Clients send it to the server, which broadcasts it to every client on
+/// [`Channel1`]. The server fills in `sender` itself, so clients can't speak for others
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub sender: ClientId,
+    pub text: String,
+}
+
+impl ChatMessage {
+    /// Cut the text down to [`MAX_CHAT_LENGTH`] characters
+    pub fn truncate(&mut self) {
+        if let Some((index, _)) = self.text.char_indices().nth(MAX_CHAT_LENGTH) {
+            self.text.truncate(index);
+        }
+    }
+}
+
+/// Longest name in characters a client can give its entity
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Sent by a client to rename the entity it carries. The server validates the name and sets
+/// it on the `Name` of the entity, which replicates back to every client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RenameRequest {
+    pub new_name: String,
+}
+
+impl RenameRequest {
+    /// The trimmed name if it is usable, the reason it isn't otherwise
+    pub fn validated(&self) -> Result<&str, &'static str> {
+        let name = self.new_name.trim();
+        if name.is_empty() {
+            return Err("empty name");
+        }
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err("name too long");
+        }
+        if name.chars().any(char::is_control) {
+            return Err("control characters in name");
+        }
+        Ok(name)
+    }
+}
+
+/// The scene of the server, sent as RON to every client that connects so that it can spawn
+/// it locally. The networked equivalent of loading the scene file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SceneSnapshot {
+    pub ron: String,
+}
+
+/// The changes of the scene since the last [`SceneDelta`] or [`SceneSnapshot`], entities
+/// being matched by their [`NetId`]. `upserts` is a RON scene of the entities added or
+/// changed, which replace the entities with the same id, and `removed` lists the entities to
+/// despawn
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SceneDelta {
+    pub upserts: String,
+    pub removed: Vec<NetId>,
+}
+
+/// Sent by a client to get a fresh [`SceneSnapshot`], e.g. to resync without reconnecting.
+/// The server rate limits it per client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RequestSceneSnapshot;
+
+/// Parse a RON scene held in memory
+pub fn parse_ron_scene(
+    text: &str,
+    app_type_registry: &AppTypeRegistry,
+) -> Result<DynamicScene, String> {
+    let type_registry = app_type_registry.read();
+    let mut deserializer =
+        bevy::scene::ron::de::Deserializer::from_str(text).map_err(|err| err.to_string())?;
+    SceneDeserializer {
+        type_registry: &type_registry,
+    }
+    .deserialize(&mut deserializer)
+    .map_err(|err| err.to_string())
+}
+
+/// How big the pieces of a large payload are, see [`split_into_fragments`].
+///
+/// Lightyear already splits messages that don't fit in a packet, but its fragment size is a
+/// constant of the crate. Payloads that can grow without bound (like a scene) are split by us
+/// instead, into [`Fragment`] messages of at most `fragment_size` bytes. Keep it under the
+/// MTU minus the headers, around 1200 bytes.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct FragmentConfig {
+    pub fragment_size: usize,
+}
+
+impl Default for FragmentConfig {
+    fn default() -> Self {
+        Self {
+            fragment_size: 1024,
+        }
     }
 }
 
+/// A piece of a payload too large for a single message. Every fragment of a payload shares
+/// its `payload_id`, `index` goes from 0 to `count - 1`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Fragment {
+    pub payload_id: u32,
+    pub index: u32,
+    pub count: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Split `data` into fragments of at most `fragment_size` bytes. An empty payload still gets
+/// one (empty) fragment, so that it arrives
+pub fn split_into_fragments(payload_id: u32, data: &[u8], fragment_size: usize) -> Vec<Fragment> {
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(fragment_size.max(1)).collect()
+    };
+    let count = chunks.len() as u32;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, bytes)| Fragment {
+            payload_id,
+            index: index as u32,
+            count,
+            bytes: bytes.to_vec(),
+        })
+        .collect()
+}
+
+/// Most fragments a payload may be split in, 64 MiB with the default [`FragmentConfig`]
+pub const MAX_FRAGMENTS: u32 = 65_536;
+
+/// Most payloads a [`FragmentReassembler`] keeps pieces of at once. When a fragment of one
+/// more arrives, the oldest incomplete payload is dropped
+pub const MAX_PENDING_PAYLOADS: usize = 4;
+
+/// Why a [`FragmentReassembler`] refused a [`Fragment`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FragmentError {
+    /// `count` is 0 or above [`MAX_FRAGMENTS`]
+    InvalidCount(u32),
+    /// `index` isn't below `count`
+    IndexOutOfRange { index: u32, count: u32 },
+    /// The earlier fragments of the payload announced another `count`
+    CountMismatch { expected: u32, count: u32 },
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentError::InvalidCount(count) => {
+                write!(f, "invalid fragment count {count} (max {MAX_FRAGMENTS})")
+            }
+            FragmentError::IndexOutOfRange { index, count } => {
+                write!(
+                    f,
+                    "fragment index {index} out of range for {count} fragments"
+                )
+            }
+            FragmentError::CountMismatch { expected, count } => {
+                write!(f, "fragment count {count} while the payload has {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FragmentError {}
+
+/// Puts the payloads split by [`split_into_fragments`] back together, whatever the order
+/// their fragments come in. At most [`MAX_PENDING_PAYLOADS`] incomplete payloads are kept
+#[derive(Default, Debug)]
+pub struct FragmentReassembler {
+    partial: HashMap<u32, Vec<Option<Vec<u8>>>>,
+    /// Ids of the incomplete payloads, oldest first
+    order: VecDeque<u32>,
+}
+
+impl FragmentReassembler {
+    /// Store a fragment, and return the whole payload once its last missing fragment arrived.
+    /// Fragments with a header that doesn't fit the payload are rejected
+    pub fn push(&mut self, fragment: Fragment) -> Result<Option<Vec<u8>>, FragmentError> {
+        let count = fragment.count;
+        if count == 0 || count > MAX_FRAGMENTS {
+            return Err(FragmentError::InvalidCount(count));
+        }
+        if fragment.index >= count {
+            return Err(FragmentError::IndexOutOfRange {
+                index: fragment.index,
+                count,
+            });
+        }
+        if !self.partial.contains_key(&fragment.payload_id) {
+            if self.order.len() >= MAX_PENDING_PAYLOADS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.partial.remove(&oldest);
+                }
+            }
+            self.order.push_back(fragment.payload_id);
+            self.partial
+                .insert(fragment.payload_id, vec![None; count as usize]);
+        }
+        let pieces = self.partial.get_mut(&fragment.payload_id).unwrap();
+        if pieces.len() != count as usize {
+            return Err(FragmentError::CountMismatch {
+                expected: pieces.len() as u32,
+                count,
+            });
+        }
+        pieces[fragment.index as usize] = Some(fragment.bytes);
+        if pieces.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        self.order.retain(|id| *id != fragment.payload_id);
+        let pieces = self
+            .partial
+            .remove(&fragment.payload_id)
+            .unwrap_or_default();
+        Ok(Some(pieces.into_iter().flatten().flatten().collect()))
+    }
+}
+
+/// How far a player moves per second when holding a direction
+pub const PLAYER_SPEED: f32 = 5.0;
+
+/// The movement a client asks for, sent every tick while a direction is held and once more
+/// when it is released. It moves the [`NetPosition`] of the entity the client carries, and
+/// the server keeps applying the last one until the next arrives, for a few ticks at most in
+/// case the release was lost
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerInput {
+    /// The client tick the input was sampled at, which the server applies it at
+    pub tick: Tick,
+    /// Normalized, or zero when no direction is held
+    pub direction: Vec2,
+}
+
 #[derive(Clone)]
 pub struct SharedPlugin;
 
+/// Registers the component `C` for replication in the given direction, and for reflection so
+/// that it can be saved in scenes.
+///
+/// Components that need prediction or interpolation are still registered by hand, as their
+/// registration has more to it.
+pub struct ReplicatePlugin<C> {
+    direction: ChannelDirection,
+    marker: PhantomData<fn() -> C>,
+}
+
+impl<C> ReplicatePlugin<C> {
+    pub fn new(direction: ChannelDirection) -> Self {
+        Self {
+            direction,
+            marker: PhantomData,
+        }
+    }
+
+    /// Replicated from the server to the clients
+    pub fn server_to_client() -> Self {
+        Self::new(ChannelDirection::ServerToClient)
+    }
+
+    /// Replicated from the clients to the server
+    pub fn client_to_server() -> Self {
+        Self::new(ChannelDirection::ClientToServer)
+    }
+}
+
+impl<C> Plugin for ReplicatePlugin<C>
+where
+    C: Component + PartialEq + Serialize + DeserializeOwned + GetTypeRegistration,
+{
+    fn build(&self, app: &mut App) {
+        app.register_component::<C>(self.direction);
+        app.register_type::<C>();
+    }
+}
+
+/// Reliable and ordered: everything sent on it arrives, in order. Used for the handshake,
+/// lobby and chat style messages where losing one isn't an option
 #[derive(Channel)]
 pub struct Channel1;
 
-#[derive(Component, Serialize, Deserialize, Reflect, PartialEq, Eq)]
+/// Unreliable and unordered: messages may be lost or arrive out of order, but a lost packet
+/// never holds back the ones after it (no head-of-line blocking) and nothing gets
+/// retransmitted. Used for the high-frequency movement inputs, where a newer message makes
+/// older ones irrelevant anyway, and for [`Ping`] and [`Pong`] so that the measured RTT is
+/// the one of a single packet.
+///
+/// Component replication doesn't go through user channels, lightyear sends entity updates on
+/// its own channels.
+#[derive(Channel)]
+pub struct Channel2;
+
+/// The carrier of an entity predicts its `ComponentA`: the predicted copy is updated in
+/// `FixedUpdate` at [`FIXED_TIMESTEP_HZ`] (or [`TICK_HZ_ENV`]), running a few ticks ahead of
+/// the server (about half the RTT plus a jitter margin). Everyone else interpolates it between
+/// server snapshots
+#[derive(Component, Serialize, Deserialize, Reflect, Clone, Debug, PartialEq, Eq)]
 #[reflect(Component)]
 pub struct ComponentA(pub usize);
 
-#[derive(Component, Serialize, Deserialize, Reflect, PartialEq, Eq)]
+/// Blend between two `ComponentA` values. Used to interpolate between server snapshots, and
+/// to smooth the predicted value towards the corrected one after a rollback instead of
+/// snapping to it
+fn lerp_component_a(start: &ComponentA, other: &ComponentA, t: f32) -> ComponentA {
+    let value = start.0 as f32 + (other.0 as f32 - start.0 as f32) * t;
+    ComponentA(value.round() as usize)
+}
+
+/// A second gameplay value next to [`ComponentA`], to check that every component of an
+/// entity replicates and gets saved, not only the first one registered
+#[derive(Component, Serialize, Deserialize, Reflect, Clone, Copy, Debug, Default, PartialEq)]
+#[reflect(Component)]
+pub struct ComponentB(pub f32);
+
+/// Identity of a replicated entity that is the same on the server and on every client, and
+/// survives saving and loading the scene, unlike `Entity` ids. Given once by the server from
+/// its [`NetIdAllocator`] (saved scenes keep theirs) and held in the
+/// [`CarrierIndex`](crate::server::CarrierIndex)
+#[derive(Component, Serialize, Deserialize, Reflect, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Component)]
+pub struct NetId(pub u64);
+
+/// Hands out the [`NetId`]s in increasing order, so that two entities never share one. The ids
+/// that come from elsewhere (a loaded scene) are [`reserved`](Self::reserve) to be skipped
+#[derive(Resource, Debug, Default)]
+pub struct NetIdAllocator {
+    next: u64,
+}
+
+impl NetIdAllocator {
+    pub fn allocate(&mut self) -> NetId {
+        let id = NetId(self.next);
+        self.next += 1;
+        id
+    }
+
+    /// Only hand out ids above `id` from now on
+    pub fn reserve(&mut self, id: NetId) {
+        self.next = self.next.max(id.0.saturating_add(1));
+    }
+}
+
+#[derive(Component, Serialize, Deserialize, Reflect, Clone, Copy, Debug, PartialEq, Eq)]
 #[reflect(Component)]
 pub struct CarrierId(pub ClientId);
 
+/// Position of a player in the world. Predicted and interpolated like [`ComponentA`], the
+/// client renders a cube there
+#[derive(Component, Serialize, Deserialize, Reflect, Clone, Copy, Debug, Default, PartialEq)]
+#[reflect(Component)]
+pub struct NetPosition(pub Vec3);
+
+fn lerp_net_position(start: &NetPosition, other: &NetPosition, t: f32) -> NetPosition {
+    NetPosition(start.0.lerp(other.0, t))
+}
+
+/// A color picked from `bits` alone. The bits are hashed to a hue so that consecutive values
+/// still get far apart colors
+pub fn hashed_color(bits: u64) -> Color {
+    let hash = bits.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let hue = (hash >> 40) as f32 / (1u64 << 24) as f32 * 360.0;
+    Color::hsl(hue, 0.7, 0.6)
+}
+
+/// Players spawn spread on a circle of this radius around the origin
+pub const SPAWN_RADIUS: f32 = 3.0;
+
+/// Where the entity of `client_id` starts, on a circle around the origin with room for 8
+/// players before they start to overlap
+pub fn spawn_position(client_id: ClientId) -> Vec3 {
+    let slot = (client_id.to_bits() % 8) as f32;
+    let angle = slot * std::f32::consts::TAU / 8.0;
+    Vec3::new(angle.cos(), angle.sin(), 0.0) * SPAWN_RADIUS
+}
+
 impl Plugin for SharedPlugin {
     fn build(&self, app: &mut App) {
-        app.add_channel::<Channel1>(ChannelSettings {
-            mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
-            ..default()
-        });
-
-        // Registering component A which is gonna be basically our entity
-        app.register_component::<ComponentA>(ChannelDirection::ServerToClient);
-        app.register_component::<CarrierId>(ChannelDirection::ServerToClient);
-        app.register_component::<Name>(ChannelDirection::ServerToClient);
-        // Debug and save
-
-        app.register_type::<ComponentA>();
-        app.register_type::<CarrierId>();
+        // Seeded randomness, an `RngSeed` inserted before this plugin takes precedence
+        let seed = app
+            .world()
+            .get_resource::<RngSeed>()
+            .copied()
+            .unwrap_or_else(RngSeed::from_env);
+        app.insert_resource(seed);
+        app.insert_resource(SharedRng(StdRng::seed_from_u64(seed.0)));
+
+        app.insert_resource(CurrentTick(Tick(0)));
+        // before `FixedUpdate`, so that every system of the tick sees it
+        app.add_systems(FixedPreUpdate, update_current_tick);
+        app.add_systems(Update, log_current_tick);
+
+        register_protocol(app);
+
+        // Resources mirrored from the server
+        app.init_resource::<GameScore>();
+
+        app.init_resource::<FragmentConfig>();
+    }
+}
+
+/// Inserted by [`register_protocol`] so that registering the protocol again does nothing
+#[derive(Resource)]
+struct ProtocolRegistered;
+
+/// Register the channels, components and messages the client and the server exchange, and
+/// nothing else. [`SharedPlugin`] calls it on both sides, call it directly to get the exact
+/// same protocol without the rest of the shared behavior. Calling it again on the same app
+/// does nothing.
+///
+/// The lightyear client or server plugins have to be added first.
+pub fn register_protocol(app: &mut App) {
+    if app.world().contains_resource::<ProtocolRegistered>() {
+        debug!("The protocol is already registered");
+        return;
+    }
+    app.insert_resource(ProtocolRegistered);
+    app.add_channel::<Channel1>(ChannelSettings {
+        mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+        ..default()
+    });
+    app.add_channel::<Channel2>(ChannelSettings {
+        mode: ChannelMode::UnorderedUnreliable,
+        ..default()
+    });
+
+    // ComponentA is Bidirectional because a client given the authority over its carrier
+    // replicates it back to the server. The owning client predicts it: when the server's
+    // confirmed value differs from the prediction for that tick, lightyear rolls back and
+    // the correction fn blends the result in. The other clients interpolate it
+    app.register_component::<ComponentA>(ChannelDirection::Bidirectional)
+        .add_prediction(ComponentSyncMode::Full)
+        .add_correction_fn(lerp_component_a)
+        .add_interpolation(ComponentSyncMode::Full)
+        .add_interpolation_fn(lerp_component_a);
+    app.add_plugins(ReplicatePlugin::<ComponentB>::server_to_client());
+    app.add_plugins(ReplicatePlugin::<CarrierId>::server_to_client());
+    app.add_plugins(ReplicatePlugin::<NetId>::server_to_client());
+    // no correction fn: the client smooths the position corrections itself, see
+    // `client::VisualError`
+    app.register_component::<NetPosition>(ChannelDirection::ServerToClient)
+        .add_prediction(ComponentSyncMode::Full)
+        .add_interpolation(ComponentSyncMode::Full)
+        .add_interpolation_fn(lerp_net_position);
+    app.register_component::<Name>(ChannelDirection::ServerToClient);
+    // Removals need no extra setting: removing a registered component from a replicated
+    // entity on the server removes it on the client too, including from the predicted and
+    // interpolated copies. It's only skipped for components whose replication was
+    // disabled on that entity
+    // Debug and save
+
+    app.register_type::<ComponentA>();
+    app.register_type::<NetPosition>();
+
+    // Handshake
+    app.register_message::<ClientHello>(ChannelDirection::ClientToServer);
+
+    // Inputs
+    app.register_message::<PlayerInput>(ChannelDirection::ClientToServer);
+
+    // Chat
+    app.register_message::<ChatMessage>(ChannelDirection::Bidirectional);
+
+    // Names are replicated from the server only, clients ask for a change instead
+    app.register_message::<RenameRequest>(ChannelDirection::ClientToServer);
+
+    // Resources mirrored from the server
+    app.register_message::<GameScore>(ChannelDirection::ServerToClient);
+
+    // Scene
+    app.register_message::<SceneSnapshot>(ChannelDirection::ServerToClient);
+    app.register_message::<SceneDelta>(ChannelDirection::ServerToClient);
+    app.register_message::<RequestSceneSnapshot>(ChannelDirection::ClientToServer);
+
+    // Large payloads (scene snapshots), sent piece by piece
+    app.register_message::<Fragment>(ChannelDirection::ServerToClient);
+
+    // Latency
+    app.register_message::<Ping>(ChannelDirection::ClientToServer);
+    app.register_message::<Pong>(ChannelDirection::ServerToClient);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_ids_skip_the_reserved_ones() {
+        let mut allocator = NetIdAllocator::default();
+        assert_eq!(allocator.allocate(), NetId(0));
+        allocator.reserve(NetId(41));
+        allocator.reserve(NetId(7));
+        assert_eq!(allocator.allocate(), NetId(42));
+        assert_eq!(allocator.allocate(), NetId(43));
+    }
+
+    fn fragment(payload_id: u32, index: u32, count: u32) -> Fragment {
+        Fragment {
+            payload_id,
+            index,
+            count,
+            bytes: vec![index as u8],
+        }
+    }
+
+    #[test]
+    fn reassembler_rejects_bad_headers() {
+        let mut reassembler = FragmentReassembler::default();
+        assert_eq!(
+            reassembler.push(fragment(0, 0, 0)),
+            Err(FragmentError::InvalidCount(0))
+        );
+        assert_eq!(
+            reassembler.push(fragment(0, 0, MAX_FRAGMENTS + 1)),
+            Err(FragmentError::InvalidCount(MAX_FRAGMENTS + 1))
+        );
+        assert_eq!(
+            reassembler.push(fragment(0, 2, 2)),
+            Err(FragmentError::IndexOutOfRange { index: 2, count: 2 })
+        );
+        assert_eq!(reassembler.push(fragment(0, 0, 2)), Ok(None));
+        assert_eq!(
+            reassembler.push(fragment(0, 1, 3)),
+            Err(FragmentError::CountMismatch {
+                expected: 2,
+                count: 3
+            })
+        );
+        assert_eq!(reassembler.push(fragment(0, 1, 2)), Ok(Some(vec![0, 1])));
+    }
+
+    #[test]
+    fn reassembler_drops_the_oldest_pending_payload() {
+        let mut reassembler = FragmentReassembler::default();
+        for payload_id in 0..=MAX_PENDING_PAYLOADS as u32 {
+            assert_eq!(reassembler.push(fragment(payload_id, 0, 2)), Ok(None));
+        }
+        assert_eq!(reassembler.partial.len(), MAX_PENDING_PAYLOADS);
+        // the first payload was dropped, its second half starts it over
+        assert_eq!(reassembler.push(fragment(0, 1, 2)), Ok(None));
+        assert_eq!(reassembler.push(fragment(2, 1, 2)), Ok(Some(vec![0, 1])));
+    }
+
+    #[test]
+    fn packet_loss_drops_sent_packets() {
+        for (loss, expected) in [(0.0, 100), (1.0, 0)] {
+            let (to_client, from_server) = crossbeam_channel::unbounded();
+            let (_to_server, from_client) = crossbeam_channel::unbounded();
+            let io = server::IoConfig::from_transport(server::ServerTransport::Channels {
+                channels: vec![(SERVER_ADDR, from_client, to_client)],
+            });
+            let server::ServerTransport::Channels { channels } =
+                with_packet_loss(io, loss).transport
+            else {
+                unreachable!();
+            };
+            for packet in 0..100u8 {
+                channels[0].2.send(vec![packet]).unwrap();
+            }
+            drop(channels);
+            // the forwarding thread stops, and the channel closes, once the sender is dropped
+            assert_eq!(from_server.iter().count(), expected, "loss {}", loss);
+        }
+    }
+
+    /// Registering the protocol a second time leaves the first registration alone instead of
+    /// panicking on the plugins added twice
+    #[test]
+    fn protocol_registers_once() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, bevy::state::app::StatesPlugin));
+        app.add_plugins(server::ServerPlugins::new(server::ServerConfig::default()));
+        register_protocol(&mut app);
+        register_protocol(&mut app);
+        assert!(app.world().contains_resource::<ProtocolRegistered>());
     }
 }
@@ -0,0 +1,119 @@
+//! A stress test of the server: many simulated clients connect at once.
+//!
+//! It's ignored by default, run it alone and in release for meaningful numbers:
+//! `cargo test --release stress -- --ignored --nocapture`.
+//!
+//! The clients don't go through any transport, a connect event is sent for each of them
+//! along with the entity they carry, so this only measures the server side: `add_replicate`
+//! and everything else reacting to connects.
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+use std::time::{Duration, Instant};
+
+use crate::server::{ConnectionLimits, Replicated};
+use crate::shared::{shared_config, CarrierId, ComponentA};
+use crate::test_utils::Stepper;
+
+/// How many simulated clients connect
+const CLIENTS: usize = 100;
+
+/// How long the server keeps running after the clients connected, in server time
+const RUN_FOR: Duration = Duration::from_secs(3);
+
+/// The frame handling all the connects must take less than this
+const CONNECT_BUDGET: Duration = Duration::from_millis(500);
+
+/// How much the resident memory of the process may grow over the run
+const MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+/// The resident memory of the process in bytes, on Linux only
+fn resident_memory() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Connect [`CLIENTS`] simulated clients, run the server for a few seconds, and check that
+/// every client got its entity replicated within the time and memory budgets
+#[test]
+#[ignore = "slow, run it alone in release"]
+fn hundred_clients_stay_within_budget() {
+    let mut stepper = Stepper::with_server(&[0], |server| {
+        server.insert_resource(ConnectionLimits {
+            max_clients: CLIENTS + 1,
+            ..default()
+        });
+    });
+    let memory_before = resident_memory();
+
+    let client_ids: HashSet<ClientId> = (1..=CLIENTS as u64).map(ClientId::Netcode).collect();
+    let world = stepper.server_world();
+    for &client_id in &client_ids {
+        world.spawn((
+            ComponentA(0),
+            CarrierId(client_id),
+            Name::new("Replicated entity"),
+        ));
+        world.send_event(ServerConnectEvent {
+            client_id,
+            entity: Entity::PLACEHOLDER,
+        });
+    }
+    let connected_at = Instant::now();
+    stepper.step();
+    let connect_time = connected_at.elapsed();
+
+    // the frames after the connects must take less than a tick on average, or the server
+    // falls behind. They include the millisecond the stepper sleeps
+    let tick_budget = shared_config().tick.tick_duration;
+    let frames = (RUN_FOR.as_secs_f64() / tick_budget.as_secs_f64()) as u32;
+    let mut slowest = Duration::ZERO;
+    let mut total = Duration::ZERO;
+    for _ in 0..frames {
+        let frame_start = Instant::now();
+        stepper.step();
+        let frame_time = frame_start.elapsed();
+        slowest = slowest.max(frame_time);
+        total += frame_time;
+    }
+    let average = total / frames;
+    let memory_growth = resident_memory()
+        .zip(memory_before)
+        .map(|(after, before)| after.saturating_sub(before));
+
+    let world = stepper.server_world();
+    let replicated = world
+        .query_filtered::<&CarrierId, With<Replicated>>()
+        .iter(world)
+        .filter(|carrier_id| client_ids.contains(&carrier_id.0))
+        .count();
+    info!(
+        "{} clients: {} entities replicated, the connect frame took {:?}, then {:?} per frame \
+         ({:?} at worst), memory grew by {:?} bytes",
+        CLIENTS, replicated, connect_time, average, slowest, memory_growth
+    );
+    assert_eq!(replicated, CLIENTS);
+    assert!(
+        connect_time < CONNECT_BUDGET,
+        "the connect frame took {:?}, over the budget of {:?}",
+        connect_time,
+        CONNECT_BUDGET
+    );
+    assert!(
+        average < tick_budget,
+        "frames took {:?} on average, over the budget of {:?}",
+        average,
+        tick_budget
+    );
+    if let Some(memory_growth) = memory_growth {
+        assert!(
+            memory_growth < MEMORY_BUDGET,
+            "memory grew by {} bytes, over the budget of {}",
+            memory_growth,
+            MEMORY_BUDGET
+        );
+    }
+}
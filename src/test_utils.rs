@@ -0,0 +1,243 @@
+//! Helpers shared by the tests of the other modules
+use bevy::ecs::system::RunSystemOnce;
+use bevy::prelude::*;
+use bevy::state::app::StatesPlugin;
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::{Duration, Instant};
+use lightyear::prelude::client::{
+    Authentication, ClientCommands, ClientConfig, ClientPlugins, ClientTransport, NetConfig,
+};
+use lightyear::prelude::server::{ServerConfig, ServerPlugins};
+use lightyear::prelude::*;
+use lightyear::transport::LOCAL_SOCKET;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+
+use crate::server::{
+    BanList, ExampleServerPlugin, RoomPersistence, SceneLoadConfig, SceneSaveConfig, TransportKind,
+};
+use crate::shared::{key_to_hex, shared_config, SharedPlugin, PRIVATE_KEY_ENV};
+
+/// The type registry of an app with our protocol registered, as the server and the clients
+/// have it
+pub fn protocol_registry() -> AppTypeRegistry {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, StatesPlugin));
+    app.add_plugins(ServerPlugins::new(ServerConfig::default()));
+    app.add_plugins(SharedPlugin);
+    app.world().resource::<AppTypeRegistry>().clone()
+}
+
+/// The key of every test server, set in [`PRIVATE_KEY_ENV`] where the server reads it
+const TEST_KEY: [u8; 32] = [7; 32];
+
+/// How many frames [`Stepper::new`] waits for the clients to connect and sync
+const CONNECT_FRAMES: usize = 500;
+
+/// A headless [`ExampleServerPlugin`] and bare lightyear clients connected to it through
+/// in-process channels, all stepped by hand one tick at a time.
+///
+/// The files the server writes (scenes, bans, rooms) go to a directory of its own, and the
+/// default scene is spawned as no scene file is found there.
+pub struct Stepper {
+    pub server: App,
+    pub clients: Vec<App>,
+    /// Where the server writes its files
+    pub dir: PathBuf,
+    now: Instant,
+    frame: Duration,
+}
+
+impl Stepper {
+    /// A server with one transport per entry of `endpoints`, and as many clients connected
+    /// to each transport as the entry says. The clients get the ids 0, 1, ... in order, the
+    /// first one carries the entity of the default scene
+    pub fn new(endpoints: &[usize]) -> Self {
+        Self::with_server(endpoints, |_| {})
+    }
+
+    /// Same as [`Stepper::new`], `setup` inserts the resources the server plugin reads
+    /// before it is added
+    pub fn with_server(endpoints: &[usize], setup: impl FnOnce(&mut App)) -> Self {
+        let mut stepper = Self::starting(endpoints, setup);
+        let synced = stepper.step_until(CONNECT_FRAMES, |stepper| {
+            (0..stepper.clients.len()).all(|index| stepper.is_synced(index))
+        });
+        assert!(synced, "the clients didn't connect");
+        stepper
+    }
+
+    /// Same as [`Stepper::with_server`], but returns once the clients started connecting,
+    /// for the tests where some of them are refused
+    pub fn starting(endpoints: &[usize], setup: impl FnOnce(&mut App)) -> Self {
+        static SET_KEY: Once = Once::new();
+        SET_KEY.call_once(|| std::env::set_var(PRIVATE_KEY_ENV, key_to_hex(&TEST_KEY)));
+        static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "mre_scene_test_{}_{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let now = Instant::now();
+        let mut server = App::new();
+        server.insert_resource(SceneSaveConfig {
+            path: dir.join("scene.ron"),
+            ..default()
+        });
+        server.insert_resource(SceneLoadConfig {
+            path: PathBuf::from("missing_test_scene.ron"),
+            ..default()
+        });
+        server.insert_resource(BanList {
+            path: dir.join("bans.ron"),
+            ..default()
+        });
+        server.insert_resource(RoomPersistence {
+            path: dir.join("rooms.ron"),
+            ..default()
+        });
+        setup(&mut server);
+
+        let mut builder = ExampleServerPlugin::builder().headless(true);
+        let mut clients = Vec::new();
+        for (endpoint, &count) in endpoints.iter().enumerate() {
+            let mut channels = Vec::new();
+            for index in 0..count {
+                let client_id = clients.len() as u64;
+                let (to_server, from_client) = crossbeam_channel::unbounded();
+                let (to_client, from_server) = crossbeam_channel::unbounded();
+                let client_addr = SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    10_000 + (endpoint * 100 + index) as u16,
+                );
+                channels.push((client_addr, from_client, to_client));
+                clients.push(client_app(client_id, to_server, from_server, now));
+            }
+            builder = builder.transport(TransportKind::Channels { channels });
+        }
+        server.add_plugins(builder.build());
+        server
+            .world_mut()
+            .resource_mut::<Time<Real>>()
+            .update_with_instant(now);
+
+        let mut stepper = Self {
+            server,
+            clients,
+            dir,
+            now,
+            frame: shared_config().tick.tick_duration,
+        };
+        stepper.start();
+        stepper
+    }
+
+    /// Start the server and have every client connect
+    fn start(&mut self) {
+        self.server.finish();
+        self.server.cleanup();
+        for client in &mut self.clients {
+            client.finish();
+            client.cleanup();
+            client
+                .world_mut()
+                .run_system_once(|mut commands: Commands| commands.connect_client())
+                .unwrap();
+        }
+    }
+
+    /// Whether the client at `index` is connected and synced with the server
+    pub fn is_synced(&self, index: usize) -> bool {
+        self.clients[index]
+            .world()
+            .resource::<client::ConnectionManager>()
+            .is_synced()
+    }
+
+    /// Advance the clock by a tick and update the clients, then the server
+    pub fn step(&mut self) {
+        self.now += self.frame;
+        for client in &mut self.clients {
+            client.insert_resource(TimeUpdateStrategy::ManualInstant(self.now));
+            client.update();
+        }
+        self.server
+            .insert_resource(TimeUpdateStrategy::ManualInstant(self.now));
+        self.server.update();
+        // leave the asset and io threads a chance to run
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    /// Step until `done` holds, at most `frames` times. Returns whether it held
+    pub fn step_until(&mut self, frames: usize, mut done: impl FnMut(&mut Self) -> bool) -> bool {
+        for _ in 0..frames {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+
+    pub fn client_world(&mut self, index: usize) -> &mut World {
+        self.clients[index].world_mut()
+    }
+
+    pub fn server_world(&mut self) -> &mut World {
+        self.server.world_mut()
+    }
+}
+
+impl Drop for Stepper {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A client app with only the lightyear plugins and our protocol, talking to the server
+/// through the given channels
+fn client_app(
+    client_id: u64,
+    to_server: crossbeam_channel::Sender<Vec<u8>>,
+    from_server: crossbeam_channel::Receiver<Vec<u8>>,
+    now: Instant,
+) -> App {
+    let auth = Authentication::Manual {
+        server_addr: LOCAL_SOCKET,
+        client_id,
+        private_key: TEST_KEY,
+        protocol_id: crate::shared::protocol_id(),
+    };
+    let io = client::IoConfig::from_transport(ClientTransport::LocalChannel {
+        recv: from_server,
+        send: to_server,
+    });
+    let config = ClientConfig {
+        shared: shared_config(),
+        net: NetConfig::Netcode {
+            auth,
+            io,
+            config: default(),
+        },
+        ..default()
+    };
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        StatesPlugin,
+        TransformPlugin,
+        HierarchyPlugin,
+        AssetPlugin::default(),
+        bevy::scene::ScenePlugin,
+    ));
+    app.add_plugins(ClientPlugins::new(config));
+    app.add_plugins(SharedPlugin);
+    app.world_mut()
+        .resource_mut::<Time<Real>>()
+        .update_with_instant(now);
+    app
+}